@@ -1,47 +1,276 @@
-use std::cell::RefCell;
-use std::collections::HashSet;
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
-use std::io::Write;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Result};
+use chrono::{FixedOffset, NaiveTime, TimeZone, Utc};
 use futures::StreamExt;
 use hyper::client::HttpConnector;
-use hyper::service::service_fn;
 use hyper::Client;
 use hyper_proxy::{Intercept, Proxy, ProxyConnector};
-use log::{debug, error, info, warn};
+use log::{debug, error, info, log, trace, warn, Level};
 use telegram_bot::connector::default_connector;
 use telegram_bot::connector::hyper::HyperConnector;
 use telegram_bot::MessageEntityKind::BotCommand;
 use telegram_bot::*;
 use serde::{Serialize, Deserialize};
+use serde_json::json;
+use tokio::{signal, time};
 
+use super::budget::{self, Budget};
 use super::cache;
+use super::command::{self, Command, Value};
 use super::config;
-use telegram_bot::ParseMode::Markdown;
+use super::critical::Critical;
+use super::deadletter::{Action, DeadLetter};
+use super::filter;
+use super::hook;
+use super::limiter::Limiter;
+use super::normalize::normalize;
+use super::requests::{
+    ApiError, ApproveChatJoinRequest, ChatJoinRequest, ChatPermissions, DeclineChatJoinRequest,
+    GetPinnedMessage, RawUpdate, RestrictChatMember, UnpinMessage,
+};
+use super::script;
+use super::spam_score::Scorer;
+use super::stats;
+use super::wave::WaveDetector;
 
+const ADMINS_PAGE_SIZE: usize = 20;
+const DEADLETTER_LIST_SIZE: usize = 20;
+const OPEN_FLAGS_LIST_SIZE: usize = 20;
+const MEMBER_COUNT_TTL: Integer = 3600;
+// Admin rechecks of callbacks are reused for a while, so that pressing a few
+// buttons in a row won't delay answering each of them.
+const ADMIN_CHECK_TTL: Integer = 60;
+const CHAT_INFO_TTL: Integer = 86400;
+// Telegram doesn't allow editing messages older than 48 hours.
+const EDIT_WINDOW: Integer = 48 * 3600;
+// Handled commands are pruned once more commands than this are tracked.
+const HANDLED_COMMANDS_PRUNE_THRESHOLD: usize = 1024;
+const SUMMARY_TOP_AUTHORS: usize = 5;
+// Longer button labels are cut off by telegram clients.
+const MAX_BUTTON_LABEL_LEN: usize = 64;
+// Telegram rejects buttons with longer callback data.
+const MAX_CALLBACK_DATA_LEN: usize = 64;
+// Max seconds of a long poll, telegram keeps polls around this long at
+// most.
+const MAX_POLL_TIMEOUT: u64 = 50;
+// Payload of `/start` from appeal buttons.
+const APPEAL_START: &str = "appeal";
+// Seconds that the next private message after starting an appeal is taken
+// as the appeal.
+const APPEAL_WINDOW: Integer = 3600;
+// Sender name of the message cached by selftest.
+const SELFTEST_USER: &str = "onobot selftest";
+// Items of an album arrive within seconds.
+const ALBUM_TTL: Integer = 60;
+// Digests are sent early before exceeding telegram's 4096 chars limit.
+const AUDIT_DIGEST_LEN: usize = 3000;
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct API {
-    api: Api,
+    api: budget::Client,
     cfg: config::Config,
+    me: User,
+    // Label of this instance in pages and summaries.
+    instance: String,
 
     cache: RefCell<cache::Cache>,
-    admins: HashSet<UserId>,
+    admins: RefCell<HashSet<UserId>>,
+    // On-demand admin checks of users not in admins, keyed by user ids.
+    admin_checks: RefCell<Limiter<UserId>>,
+    stats: RefCell<stats::Counters>,
+    // Alerts issued in the cooldown window, keyed according to cooldown_scope.
+    cooldown: RefCell<Limiter<i64>>,
+    // Reminders sent to original authors, keyed by their user ids.
+    user_reminders: RefCell<Limiter<UserId>>,
+    // Stickers and animations posted in main group, keyed by their senders.
+    stickers: RefCell<Limiter<UserId>>,
+    // Automatic alerts of sticker_limit, keyed by user ids.
+    sticker_alerts: RefCell<Limiter<UserId>>,
+    // Recent texts in main group for detecting spam waves.
+    waves: RefCell<WaveDetector>,
+    // Times of recent spam waves and the attack mode entered on them.
+    recent_waves: RefCell<VecDeque<Integer>>,
+    attack: RefCell<Option<AttackState>>,
+    // Spam scores of messages in main group, if enabled.
+    spam_scorer: Option<RefCell<Scorer>>,
+    // Notifications of spam scores, keyed by senders.
+    spam_alerts: RefCell<Limiter<UserId>>,
+    // Pages sent to oncall chat, keyed by category.
+    oncall: RefCell<Limiter<Critical>>,
+    // Button urls that have been reported invalid.
+    invalid_urls: RefCell<HashSet<String>>,
+    // Presses of buttons, keyed by users and kinds of callbacks.
+    presses: RefCell<Limiter<(UserId, &'static str)>>,
+    admin_presses: RefCell<Limiter<(UserId, &'static str)>>,
+    // Commands handled and the time they were sent, keyed by their messages.
+    handled_commands: RefCell<HashMap<(ChatId, MessageId), (String, Integer)>>,
+    // Commands waiting for confirmation, their codes and the time they were
+    // sent, keyed by admins sending them.
+    pending_commands: RefCell<HashMap<UserId, (Command, String, Integer)>>,
+    // Captions of recent albums and the time they were seen, keyed by media
+    // group ids.
+    albums: RefCell<HashMap<String, (Integer, String)>>,
+    // Audits waiting for a digest and the time of the first one.
+    audits: RefCell<(Integer, Vec<String>)>,
+    // Time that confirmation buttons were shown, keyed by their prompts.
+    confirms: RefCell<HashMap<(ChatId, MessageId), Integer>>,
+    // Forwards waiting for a batch prompt and the time of the first one,
+    // keyed by admins forwarding them.
+    batches: RefCell<HashMap<UserId, (Integer, Vec<Message>)>>,
+    // Alerts are not posted while paused from the menu.
+    paused: Cell<bool>,
+    // Log levels overridden for reasons, see `log_level`.
+    reason_levels: RefCell<HashMap<String, config::LogLevel>>,
+    // Time that users started appeals in private chats and the message
+    // whose alert is appealed.
+    appeals: RefCell<HashMap<UserId, (Integer, Option<MessageId>)>>,
+    // Pass of pruning cached messages going on, see `prune_batch`.
+    pruning: RefCell<Option<Pruning>>,
+    // Alert being posted, buried if shutdown cuts it off.
+    alerting: RefCell<Option<Action>>,
+    // Stale updates discarded at startup, `None` once updates are fresh.
+    stale_updates: Cell<Option<usize>>,
+    // Member counts and the time they were fetched, keyed by chat id.
+    member_counts: RefCell<HashMap<i64, (Integer, Integer)>>,
+    // Results of `still_admin` and the time they were checked.
+    admin_rechecks: RefCell<HashMap<UserId, (bool, Integer)>>,
+    // Schedule of the last daily summary, in case recording it in cache
+    // failed.
+    last_summary: Cell<Option<Integer>>,
+}
+
+/// Activity in attack mode, see `config::Attack`.
+#[derive(Debug, Clone, Copy)]
+struct AttackState {
+    since: Integer,
+    last_wave: Integer,
+    last_report: Integer,
+    waves: usize,
+    deleted: usize,
+    suppressed: usize,
+}
+
+struct Pruning {
+    expired: Integer,
+    /// Last key scanned, `None` before the first batch.
+    cursor: Option<Vec<u8>>,
+    removed: usize,
+    batches: usize,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Callback {
-    Offtopic {
+    Reason {
         id: MessageId,
-    }
+        reason: String,
+    },
+    ConfirmReason {
+        id: MessageId,
+        reason: String,
+    },
+    AdminsPage {
+        group: i64,
+        offset: usize,
+    },
+    Menu {
+        action: MenuAction,
+    },
+    /// Apply the reason to all forwards in the batch.
+    Batch {
+        id: u64,
+        reason: String,
+    },
+    ConfirmBatch {
+        id: u64,
+        reason: String,
+    },
+    /// Mark the appeal in `appeal_queue`.
+    Appeal {
+        id: u64,
+        status: cache::AppealStatus,
+    },
+    /// Decide the request of the user to join main group.
+    JoinDecision {
+        user: UserId,
+        approved: bool,
+    },
+}
+
+/// Outcome of posting an alert.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Posted {
+    /// The alert is posted, or the reason doesn't post alerts at all.
+    Sent,
+    /// The alert is skipped to keep main group quiet in attack mode.
+    Suppressed,
+}
+
+/// Why the alert of a flagged message is held back.
+enum Held {
+    Paused,
+    /// Seconds till the alert cools down.
+    CoolingDown(Integer),
+    /// The author has been reminded recently, holding whether the flag is
+    /// recorded.
+    Suppressed(Result<()>),
+}
+
+/// Quick actions in the menu posted by `/menu`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum MenuAction {
+    /// Pause or resume posting alerts.
+    Pause,
+    Stats,
+    ReloadAdmins,
 }
 
 impl Callback {
-    fn to_string(&self) -> Result<String> {
-        Ok(serde_json::to_string(self)?)
+    /// Name of the variant, presses are limited by it.
+    fn kind(&self) -> &'static str {
+        match self {
+            Callback::Reason { .. } => "reason",
+            Callback::ConfirmReason { .. } => "confirm_reason",
+            Callback::AdminsPage { .. } => "admins_page",
+            Callback::Menu { .. } => "menu",
+            Callback::Batch { .. } => "batch",
+            Callback::ConfirmBatch { .. } => "confirm_batch",
+            Callback::Appeal { .. } => "appeal",
+            Callback::JoinDecision { .. } => "join_decision",
+        }
+    }
+
+    fn encode(&self, format: config::CallbackFormat) -> Result<String> {
+        let data = match format {
+            config::CallbackFormat::Json => serde_json::to_string(self)?,
+            config::CallbackFormat::Compact => {
+                base64::encode_config(&rmp_serde::to_vec(self)?, base64::URL_SAFE_NO_PAD)
+            }
+        };
+        if data.len() > MAX_CALLBACK_DATA_LEN {
+            return Err(anyhow!(
+                "callback data of {:?} is {} bytes, longer than {}",
+                self,
+                data.len(),
+                MAX_CALLBACK_DATA_LEN
+            ));
+        }
+        Ok(data)
     }
 
-    fn from_string(s: &String) -> Result<Self> {
-        Ok(serde_json::from_str(s.as_str())?)
+    /// Decode callback data in either format, JSON always starts with `{`
+    /// which never appears in base64.
+    fn decode(s: &str) -> Result<Self> {
+        if s.starts_with('{') {
+            return Ok(serde_json::from_str(s)?);
+        }
+        let data = base64::decode_config(s, base64::URL_SAFE_NO_PAD)?;
+        Ok(rmp_serde::from_slice(&data)?)
     }
 }
 
@@ -51,7 +280,7 @@ impl API {
 
         let connector = if env::var("https_proxy").is_ok() {
             let proxy_uri = env::var("https_proxy")?.parse().unwrap();
-            let mut proxy = Proxy::new(Intercept::All, proxy_uri);
+            let proxy = Proxy::new(Intercept::All, proxy_uri);
             let connector = HttpConnector::new();
             let proxy_connector = ProxyConnector::from_proxy(connector, proxy).unwrap();
             Box::new(HyperConnector::new(
@@ -61,89 +290,800 @@ impl API {
             default_connector()
         };
 
-        let api = Api::with_connector(token, connector);
+        let api = budget::Client::new(
+            Api::with_connector(token, connector),
+            Budget::new(cfg.api_rate, cfg.api_burst),
+        );
+        let me = api.send(GetMe).await?;
 
         // Fetch admins.
         let mut h = HashSet::new();
-        match api
-            .send(GetChatAdministrators::new(ChatId::from(cfg.main_group)))
-            .await
-        {
+        match fetch_admins(&api, &cfg).await {
             Err(err) => error!("get chat administrator: {}", err.to_string()),
             Ok(admins) => {
+                warn_unusable_admins(&cfg, &admins);
                 for m in admins.iter() {
                     h.insert(m.user.id);
                 }
             }
         }
+        override_admins(&cfg, &mut h);
+
+        // Make sure every alert target is reachable before serving.
+        for reason in cfg.reasons.iter() {
+            let chat = match reason.target {
+                config::Target::Admin => cfg.admin_group,
+                config::Target::Log => cfg.log_channel.ok_or_else(|| {
+                    anyhow!("reason {} targets log but log_channel is not set", reason.id)
+                })?,
+                config::Target::Main | config::Target::None => continue,
+            };
+            if let Err(err) = api.send(GetChat::new(ChatId::from(chat))).await {
+                error!("reason {} target chat {} is not accessible: {}", reason.id, chat, err);
+            }
+        }
+
+        for label in cfg.buttons.labels() {
+            if label.is_empty() || label.chars().count() > MAX_BUTTON_LABEL_LEN {
+                return Err(anyhow!(
+                    "button label {:?} must be 1 to {} chars",
+                    label,
+                    MAX_BUTTON_LABEL_LEN
+                ));
+            }
+        }
+        for reason in &cfg.reasons {
+            match (reason.appeal, &reason.appeal_url) {
+                (config::Appeal::Url, Some(url))
+                    if url.starts_with("https://") || url.starts_with("tg://") => {}
+                (config::Appeal::Url, _) => {
+                    return Err(anyhow!(
+                        "reason {} appeals to url but appeal_url is not a https or tg link",
+                        reason.id
+                    ))
+                }
+                (config::Appeal::Dm, _) if me.username.is_none() => {
+                    return Err(anyhow!("reason {} appeals in dm but bot has no username", reason.id))
+                }
+                (config::Appeal::Meta, _) if cfg.appeal_queue.is_some() && me.username.is_none() => {
+                    return Err(anyhow!("appeal_queue is set but bot has no username"))
+                }
+                _ => {}
+            }
+            for label in reason.labels.values() {
+                if label.is_empty() || label.chars().count() > MAX_BUTTON_LABEL_LEN {
+                    return Err(anyhow!(
+                        "label {:?} of reason {} must be 1 to {} chars",
+                        label,
+                        reason.id,
+                        MAX_BUTTON_LABEL_LEN
+                    ));
+                }
+            }
+        }
+        // Check the longest callbacks of every reason before sending any
+        // button, ids are assumed to fit in 32 bits.
+        for reason in &cfg.reasons {
+            let callbacks = [
+                Callback::ConfirmReason {
+                    id: MessageId::from(Integer::from(i32::MAX)),
+                    reason: reason.id.clone(),
+                },
+                Callback::ConfirmBatch {
+                    id: u64::from(u32::MAX),
+                    reason: reason.id.clone(),
+                },
+            ];
+            for cb in callbacks.iter() {
+                cb.encode(cfg.callback_format).map_err(|err| {
+                    anyhow!(
+                        "reason id {} is too long, shorten it or use compact callback_format: {}",
+                        reason.id,
+                        err
+                    )
+                })?;
+            }
+        }
+        if let Some(id) = &cfg.default_reason {
+            if cfg.reason(id).is_none() {
+                return Err(anyhow!("default_reason {} is not a reason", id));
+            }
+        }
+        if let Some(id) = &cfg.sticker_reason {
+            if cfg.reason(id).is_none() {
+                return Err(anyhow!("sticker_reason {} is not a reason", id));
+            }
+        }
+        for name in cfg.confirm_commands.iter() {
+            match command::find(name) {
+                None => return Err(anyhow!("confirm command {} is not a command", name)),
+                Some(spec) if spec.read_only => {
+                    return Err(anyhow!("confirm command {} is read-only", name))
+                }
+                Some(_) => {}
+            }
+        }
+        for f in cfg.filters.iter() {
+            if cfg.reason(&f.reason).is_none() {
+                return Err(anyhow!("reason {} of filter {} is not a reason", f.reason, f.keyword));
+            }
+        }
+        if let Some(at) = &cfg.summary_time {
+            parse_time_of_day(at)
+                .ok_or_else(|| anyhow!("invalid summary_time {}, expect HH:MM", at))?;
+            if cfg.summary_target == config::SummaryTarget::Log && cfg.log_channel.is_none() {
+                return Err(anyhow!("summary targets log but log_channel is not set"));
+            }
+        }
+        if !(1..=MAX_POLL_TIMEOUT).contains(&cfg.poll_timeout) {
+            return Err(anyhow!(
+                "poll_timeout {} is not between 1 and {}",
+                cfg.poll_timeout,
+                MAX_POLL_TIMEOUT
+            ));
+        }
+        if !(0.0..=1.0).contains(&cfg.update_sample) {
+            return Err(anyhow!("update_sample {} is not between 0 and 1", cfg.update_sample));
+        }
+        timezone(cfg.utc_offset)
+            .ok_or_else(|| anyhow!("invalid utc_offset {}", cfg.utc_offset))?;
+        for t in cfg.timezones.iter() {
+            timezone(t.utc_offset)
+                .ok_or_else(|| anyhow!("invalid utc_offset {} of chat {}", t.utc_offset, t.chat))?;
+        }
+
+        let mut fallback = None;
+        let mut cache = match cache::Cache::new(&cfg.db) {
+            Ok(cache) => cache,
+            Err(err) if cfg.cache_fallback == config::CacheFallback::Memory => {
+                error!(
+                    "open cache {}: {}, FALLING BACK TO IN-MEMORY CACHE, cached messages will be lost on exit",
+                    &cfg.db, err
+                );
+                fallback = Some(err);
+                cache::Cache::temporary()?
+            }
+            Err(err) => return Err(err),
+        };
+        cache
+            .format(cfg.cache_format)
+            .max_text_len(cfg.max_cached_text_len)
+            .date_tolerance(cfg.forward_date_tolerance as Integer);
+        let discard = cfg.cache_migration == config::CacheMigration::Discard;
+        if let Some(m) = cache.migrate(discard)? {
+            info!(
+                "migrated cache: scanned {}, upgraded {}, discarded {}, unreadable {}",
+                m.scanned, m.upgraded, m.discarded, m.unreadable
+            );
+        }
+
+        // Restore counters so that they keep growing across restarts.
+        let counters = match cache.latest_snapshot()? {
+            Some((_, c)) => c,
+            None => stats::Counters::default(),
+        };
 
-        Ok(Self {
+        let bot = Self {
             api,
             cfg: cfg.clone(),
-            cache: RefCell::new(cache::Cache::new(&cfg.db)?),
-            admins: h,
-        })
+            me,
+            instance: cfg.instance_name(),
+            cache: RefCell::new(cache),
+            admins: RefCell::new(h),
+            admin_checks: RefCell::new(Limiter::new(cfg.admin_check_interval as Integer, 1)),
+            stats: RefCell::new(counters),
+            cooldown: RefCell::new(Limiter::new(
+                cfg.alert_cooldown as Integer,
+                cfg.alert_cooldown_limit,
+            )),
+            user_reminders: RefCell::new(Limiter::new(cfg.user_reminder_cooldown as Integer, 1)),
+            member_counts: RefCell::new(HashMap::new()),
+            admin_rechecks: RefCell::new(HashMap::new()),
+            last_summary: Cell::new(None),
+            handled_commands: RefCell::new(HashMap::new()),
+            confirms: RefCell::new(HashMap::new()),
+            pending_commands: RefCell::new(HashMap::new()),
+            audits: RefCell::new((0, Vec::new())),
+            albums: RefCell::new(HashMap::new()),
+            batches: RefCell::new(HashMap::new()),
+            paused: Cell::new(false),
+            stale_updates: Cell::new(Some(0)),
+            alerting: RefCell::new(None),
+            pruning: RefCell::new(None),
+            appeals: RefCell::new(HashMap::new()),
+            reason_levels: RefCell::new(
+                cfg.reasons
+                    .iter()
+                    .filter_map(|r| r.log_level.map(|level| (r.id.clone(), level)))
+                    .collect(),
+            ),
+            stickers: RefCell::new(Limiter::new(
+                cfg.sticker_window as Integer,
+                cfg.sticker_limit,
+            )),
+            sticker_alerts: RefCell::new(Limiter::new(cfg.sticker_window as Integer, 1)),
+            recent_waves: RefCell::new(VecDeque::new()),
+            attack: RefCell::new(None),
+            spam_scorer: cfg.spam_score.clone().map(|s| RefCell::new(Scorer::new(s))),
+            spam_alerts: RefCell::new(Limiter::new(
+                cfg.spam_score.as_ref().map_or(0, |s| s.flood_window as Integer),
+                1,
+            )),
+            waves: RefCell::new(WaveDetector::new(
+                cfg.spam_wave_window as Integer,
+                cfg.spam_wave_users,
+            )),
+            oncall: RefCell::new(Limiter::new(cfg.oncall_interval as Integer, 1)),
+            invalid_urls: RefCell::new(HashSet::new()),
+            presses: RefCell::new(Limiter::new(cfg.callback_window as Integer, cfg.callback_limit)),
+            admin_presses: RefCell::new(Limiter::new(
+                cfg.callback_window as Integer,
+                cfg.callback_admin_limit,
+            )),
+        };
+
+        if cfg.persist_limits {
+            bot.restore_limits()?;
+        }
+
+        if let Some(err) = fallback {
+            bot.page(
+                Critical::CacheOpen,
+                &format!("打开缓存 {} 失败，正在使用内存缓存：{}", &cfg.db, err),
+            )
+            .await;
+        }
+
+        info!("instance {} started as {:?}", &bot.instance, &bot.me.username);
+
+        Ok(bot)
     }
 
     pub async fn run(&self) -> Result<()> {
-        let mut stream = self.api.stream();
-
-        while let Some(update) = stream.next().await {
-            match update {
-                Err(err) => error!("fetch update: {}", err),
-                Ok(update) => match self.handle(&update).await {
-                    Ok(_) => info!("message {} handled correctly.", &update.id),
-                    Err(err) => error!("handle update {}: {}", &update.id, err),
+        let stream = self.api.updates(Duration::from_secs(self.cfg.poll_timeout));
+        tokio::pin!(stream);
+        // Fetching is paused while backing off from failures.
+        let mut backoff = time::delay_for(Duration::from_secs(0));
+        let mut backing_off = false;
+        let mut snapshot = time::interval(Duration::from_secs(self.cfg.stats_interval.max(1)));
+        let mut deadletter =
+            time::interval(Duration::from_secs(self.cfg.deadletter_interval.max(1)));
+        // Summary is checked every minute so that it's posted on time.
+        let mut summary = time::interval(Duration::from_secs(60));
+        let mut batch = time::interval(Duration::from_secs(1));
+        let mut prune = time::interval(Duration::from_millis(self.cfg.prune_pause.max(1)));
+        let shutdown = signal::ctrl_c();
+        tokio::pin!(shutdown);
+        let grace = Duration::from_secs(self.cfg.shutdown_timeout);
+
+        if self.cfg.selftest {
+            if let Err(err) = self.selftest().await {
+                error!("{}", err);
+            }
+        }
+        self.announce(|a| &a.online).await;
+        // Consecutive failures of fetching updates.
+        let mut failures = 0;
+
+        loop {
+            tokio::select! {
+                update = stream.next(), if !backing_off => match update {
+                    None => break,
+                    // Telegram only allows one poller per token.
+                    Some(Err(err))
+                        if error_code(&err) == Some(ApiError::CONFLICT)
+                            && self.cfg.on_conflict == config::OnConflict::Exit =>
+                    {
+                        self.page(Critical::Conflict, "另一个实例正在使用相同的 token 拉取更新")
+                            .await;
+                        return Err(anyhow!(
+                            "another instance is polling with this token: {}",
+                            err
+                        ));
+                    }
+                    // Token is revoked or the bot is deleted, retrying never helps.
+                    Some(Err(err)) if error_code(&err) == Some(ApiError::UNAUTHORIZED) => {
+                        self.page(Critical::Unauthorized, "token 已失效，实例已退出").await;
+                        return Err(anyhow!("token is not authorized anymore: {}", err));
+                    }
+                    Some(Err(err)) => {
+                        failures += 1;
+                        let secs = self.cfg.fetch_backoff_max.min(1 << failures.min(16));
+                        error!("fetch update: {}, retry in {}s", err, secs);
+                        backoff.reset(time::Instant::now() + Duration::from_secs(secs));
+                        backing_off = true;
+                    }
+                    Some(Ok(RawUpdate::ChatJoinRequest { id, request })) => {
+                        failures = 0;
+                        if let Err(err) = self.handle_join_request(&request).await {
+                            error!("handle join request of update {}: {}", id, err);
+                        }
+                    }
+                    Some(Ok(RawUpdate::Update(update))) => {
+                        failures = 0;
+                        let handled = self.handle(&update);
+                        tokio::pin!(handled);
+                        let result = tokio::select! {
+                            result = &mut handled => result,
+                            _ = &mut shutdown => {
+                                info!("interrupted, waiting for update {} to be handled", &update.id);
+                                match time::timeout(grace, &mut handled).await {
+                                    Ok(result) => {
+                                        if let Err(err) = result {
+                                            error!("handle update {}: {}", &update.id, err);
+                                        }
+                                    }
+                                    Err(_) => self.interrupt(&update),
+                                }
+                                break;
+                            }
+                        };
+                        match result {
+                            Ok(_) => info!("message {} handled correctly.", &update.id),
+                            Err(err) => error!("handle update {}: {}", &update.id, err),
+                        }
+                    }
+                },
+                _ = snapshot.tick() => {
+                    if let Err(err) = self.snapshot_stats() {
+                        error!("snapshot stats: {}", err)
+                    }
+                    if let Err(err) = self.save_limits() {
+                        error!("save limits: {}", err)
+                    }
+                }
+                _ = deadletter.tick() => match self.retry_dead_letters().await {
+                    Ok((0, _)) => {}
+                    Ok((total, done)) => info!("retried {} dead letters, {} succeeded", total, done),
+                    Err(err) => error!("retry dead letters: {}", err),
                 },
+                _ = summary.tick() => {
+                    if let Err(err) = self.send_summary_if_due().await {
+                        error!("send daily summary: {}", err)
+                    }
+                    self.report_attack().await;
+                }
+                _ = prune.tick() => {
+                    if let Err(err) = self.prune_step() {
+                        error!("prune cached messages: {}", err);
+                        *self.pruning.borrow_mut() = None;
+                    }
+                }
+                _ = batch.tick() => {
+                    self.flush_due_batches().await;
+                    self.flush_due_audits().await;
+                    self.lift_due_mutes().await;
+                }
+                _ = &mut backoff, if backing_off => backing_off = false,
+                _ = &mut shutdown => {
+                    info!("interrupted, shutting down");
+                    break;
+                }
             }
         }
 
+        let farewell = async {
+            self.flush_audits().await;
+            self.announce(|a| &a.offline).await;
+        };
+        if time::timeout(grace, farewell).await.is_err() {
+            warn!("final messages are not sent in {}s, skip them", grace.as_secs());
+        }
+        self.snapshot_stats()?;
+        self.save_limits()?;
+        self.cache.borrow().flush()?;
+
         Ok(())
     }
 
+    /// Exercise sending, cache and callbacks before relying on them, and
+    /// report the results to admin group.
+    pub async fn selftest(&self) -> Result<()> {
+        let mut failures = Vec::new();
+
+        // Markdown in the message checks parse mode as well.
+        let mut msg = SendMessage::new(ChatId::from(self.cfg.admin_group), "*onobot 自检中*");
+        msg.parse_mode(ParseMode::Markdown);
+        msg.disable_notification();
+        match self.api.send(msg).await {
+            Ok(MessageOrChannelPost::Message(sent)) => {
+                if message_text(&sent).map(String::as_str) != Some("onobot 自检中") {
+                    failures.push(format!("发送消息：内容不符 {:?}", message_text(&sent)));
+                }
+                if let Err(err) = self.api.send(DeleteMessage::new(sent.chat.id(), sent.id)).await {
+                    failures.push(format!("删除消息：{}", err));
+                }
+            }
+            Ok(MessageOrChannelPost::ChannelPost(_)) => {
+                failures.push("发送消息：admin_group 是频道".to_string())
+            }
+            Err(err) => failures.push(format!("发送消息：{}", err)),
+        }
+
+        // Time 0 never collides with real messages.
+        let cached = cache::CachedMessage {
+            id: MessageId::from(1),
+            text: Some("selftest".to_string()),
+            hash: None,
+        };
+        let round_trip = {
+            let mut cache = self.cache.borrow_mut();
+            cache
+                .set(0, SELFTEST_USER.to_string(), cached)
+                .and_then(|_| cache.get(0, SELFTEST_USER.to_string()))
+                .and_then(|got| cache.remove(0, SELFTEST_USER).map(|_| got))
+        };
+        match round_trip {
+            Ok(Some(got)) if got.text.as_deref() == Some("selftest") => {}
+            Ok(got) => failures.push(format!("缓存：读回 {:?}", got)),
+            Err(err) => failures.push(format!("缓存：{}", err)),
+        }
+
+        for reason in self.cfg.reasons.iter() {
+            let callback = Callback::Reason {
+                id: MessageId::from(Integer::MAX),
+                reason: reason.id.clone(),
+            };
+            let decoded = callback
+                .encode(self.cfg.callback_format)
+                .and_then(|data| Callback::decode(&data));
+            match decoded {
+                Ok(Callback::Reason { reason: got, .. }) if got == reason.id => {}
+                Ok(got) => failures.push(format!("按钮 {}：解析为 {:?}", reason.id, got)),
+                Err(err) => failures.push(format!("按钮 {}：{}", reason.id, err)),
+            }
+        }
+
+        let text = if failures.is_empty() {
+            "onobot 自检通过".to_string()
+        } else {
+            format!("onobot 自检失败：\n{}", failures.join("\n"))
+        };
+        let mut msg = SendMessage::new(ChatId::from(self.cfg.admin_group), text.as_str());
+        if !self.cfg.audit_notify {
+            msg.disable_notification();
+        }
+        if let Err(err) = self.api.send(msg).await {
+            error!("send selftest report: {}", err);
+        }
+
+        if failures.is_empty() {
+            info!("selftest passed");
+            Ok(())
+        } else {
+            Err(anyhow!("selftest failed: {}", failures.join("; ")))
+        }
+    }
+
+    /// Post the announcement text picked from `announce` config, if set.
+    pub async fn announce<F>(&self, text: F)
+    where
+        F: Fn(&config::Announce) -> &String,
+    {
+        let announce = match &self.cfg.announce {
+            Some(announce) => announce,
+            None => return,
+        };
+        let chat = announce.chat.unwrap_or(self.cfg.admin_group);
+
+        let msg = SendMessage::new(
+            ChatId::from(chat),
+            format!(
+                "{}（onobot {}，实例 {}）",
+                text(announce),
+                clap::crate_version!(),
+                self.instance
+            ),
+        );
+        if let Err(err) = self.api.send(msg).await {
+            error!("announce to chat {}: {}", chat, err);
+        }
+    }
+
     // We only handle following situation:
     //   - User is a admin
     //   - Message is forwarded to bot private chat
     //   - Bot is mentioned at admin group
     pub async fn handle(&self, u: &Update) -> Result<()> {
-        debug!("{:?}", &u);
+        let full = self.cfg.update_sample == 0.0 || is_sampled(u.id, self.cfg.update_sample);
+        match (full, self.cfg.privacy_mode) {
+            (true, false) => debug!("{:?}", &u),
+            (true, true) => debug!("{}", redacted_update(u)),
+            (false, _) => trace!("update {} of kind {}", u.id, update_kind(&u.kind)),
+        }
+
+        if let Some(discarded) = self.stale_updates.get() {
+            match self.is_stale(u) {
+                Some(true) => {
+                    debug!("update {} is stale, discard", u.id);
+                    self.stale_updates.set(Some(discarded + 1));
+                    return Ok(());
+                }
+                Some(false) => {
+                    if discarded > 0 {
+                        info!("discarded {} stale updates from downtime", discarded);
+                    }
+                    self.stale_updates.set(None);
+                }
+                None => {}
+            }
+        }
 
-        Ok(match &u.kind {
+        match &u.kind {
             UpdateKind::Message(m) => {
                 self.handle_message(m).await?
             }
+            UpdateKind::EditedMessage(m) => {
+                self.handle_edited_message(m).await?
+            }
+            UpdateKind::ChannelPost(p) => {
+                self.handle_channel_post(p).await?
+            }
             UpdateKind::CallbackQuery(c) => {
                 self.handle_callback(c).await?
             }
-            _ => {}
-        })
+            // Updates that telegram-bot can't parse, like pre-checkout
+            // queries, end up as errors or unknown.
+            kind => {
+                debug!("update {} of kind {} is not handled, ignore", u.id, update_kind(kind));
+            }
+        }
+
+        Ok(())
     }
 
-    pub fn get_original_message_id(&self, m: &Message) -> Option<MessageId> {
-        if m.forward.is_none() {
-            return None;
+    /// Check whether the update is older than `max_update_age`, `None` if
+    /// it's exempt or undated.
+    fn is_stale(&self, u: &Update) -> Option<bool> {
+        if self.cfg.max_update_age == 0 {
+            return Some(false);
         }
+        let date = match &u.kind {
+            UpdateKind::Message(m) | UpdateKind::EditedMessage(m) if parse_command(&m.kind).is_none() => {
+                m.edit_date.unwrap_or(m.date)
+            }
+            _ => return None,
+        };
+        Some(now() - date > self.cfg.max_update_age as Integer)
+    }
+
+    pub fn get_original_message_id(&self, m: &Message) -> Option<MessageId> {
+        self.get_original_message(m).map(|v| v.id)
+    }
 
-        let forward = m.forward.clone().unwrap();
-        match forward.from {
+    pub fn get_original_message(&self, m: &Message) -> Option<cache::CachedMessage> {
+        let forward = m.forward.clone()?;
+        let cached = match forward.from {
             ForwardFrom::User { user } => {
                 let mut sender = user.first_name.clone();
-                if user.last_name.is_some() {
-                    sender.push_str(" ");
-                    sender.push_str(&user.last_name.unwrap());
+                if let Some(last_name) = user.last_name {
+                    sender.push(' ');
+                    sender.push_str(&last_name);
                 }
                 self.cache.borrow_mut().get(forward.date, sender)
             }
             ForwardFrom::ChannelHiddenUser { sender_name } => {
                 self.cache.borrow_mut().get(forward.date, sender_name)
             }
-            _ => None,
+            _ => return None,
+        };
+        // Unreadable entries are as good as expired.
+        cached.unwrap_or_else(|err| {
+            warn!("read original message of message {}: {}", m.id, err);
+            None
+        })
+    }
+
+    /// Returns the command in the message if it's addressed to this bot in
+    /// a chat that accepts commands.
+    pub fn command_for_me(&self, m: &Message) -> Option<Command> {
+        let cmd = parse_command(&m.kind)?;
+        if self.addressed_to_me(&cmd)
+            && (m.chat.id() == ChatId::from(self.cfg.admin_group)
+                || matches!(m.chat, MessageChat::Private(_)))
+        {
+            Some(cmd)
+        } else {
+            None
+        }
+    }
+
+    // Commands addressed to other bots are none of our business.
+    fn addressed_to_me(&self, cmd: &Command) -> bool {
+        match (&cmd.bot, &self.me.username) {
+            (Some(bot), Some(me)) => bot.eq_ignore_ascii_case(me),
+            _ => true,
+        }
+    }
+
+    pub async fn handle_channel_post(&self, p: &ChannelPost) -> Result<()> {
+        if self.cfg.command_channel != Some(Integer::from(p.chat.id)) {
+            debug!("channel post {} is not from command channel, ignore", p.id);
+            return Ok(());
+        }
+        match parse_command(&p.kind) {
+            Some(cmd) if self.addressed_to_me(&cmd) => self.run_command(p, &cmd).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Check whether the message is a command to ignore under
+    /// `ignore_banned_commands`, including `/start appeal` and `/ot` that
+    /// anyone may send.
+    fn banned_command(&self, m: &Message) -> Result<bool> {
+        if !self.cfg.ignore_banned_commands
+            || parse_command(&m.kind).is_none()
+            || !self.cache.borrow().is_banned(m.from.id)?
+        {
+            return Ok(false);
+        }
+        warn!(
+            "User {}({}) is banned, ignore command in message {}",
+            &m.from.first_name, &m.from.id, m.id
+        );
+        Ok(true)
+    }
+
+    pub async fn handle_edited_message(&self, m: &Message) -> Result<()> {
+        let cmd = match self.command_for_me(m) {
+            Some(cmd) => cmd,
+            None => {
+                if m.chat.id() == ChatId::from(self.cfg.main_group) && self.cfg.cache_text {
+                    if let Some(text) = message_text(m) {
+                        self.record_history(m.id, m.edit_date.unwrap_or_else(now), text, true);
+                    }
+                }
+                self.recheck_edit(m).await;
+                return Ok(());
+            }
+        };
+        if self.cfg.edited_commands == config::EditedCommands::Ignore {
+            debug!("message {} is edited into command {}, ignore", m.id, &cmd.name);
+            return Ok(());
+        }
+        if self.banned_command(m)? {
+            return Ok(());
+        }
+
+        let text = format!("{} {}", cmd.name, cmd.rest);
+        if let Some((handled, _)) = self.handled_commands.borrow().get(&(m.chat.id(), m.id)) {
+            if *handled == text {
+                debug!("command {} of message {} has been handled, ignore", &cmd.name, m.id);
+                return Ok(());
+            }
+        }
+
+        self.track_command(m, text);
+        self.handle_command(m, &cmd).await
+    }
+
+    // History is best-effort like cached messages.
+    fn record_history(&self, id: MessageId, time: Integer, text: &str, edit: bool) {
+        if self.cfg.history_depth == 0 {
+            return;
+        }
+        let pushed = self
+            .cache
+            .borrow_mut()
+            .push_history(id, time, text, edit, self.cfg.history_depth);
+        if let Err(err) = pushed {
+            error!("record history of message {}: {}", id, err);
+        }
+    }
+
+    /// Check main group messages edited shortly after posting again, a
+    /// common trick is to post something innocent and edit it into spam.
+    async fn recheck_edit(&self, m: &Message) {
+        if self.cfg.edit_recheck_window == 0 || m.chat.id() != ChatId::from(self.cfg.main_group) {
+            return;
+        }
+        let cached = self.cache.borrow_mut().get(m.date, full_name(&m.from));
+        let cached = cached.unwrap_or_else(|err| {
+            warn!("read cached message {}: {}", m.id, err);
+            None
+        });
+        let window = self.cfg.edit_recheck_window as Integer;
+        let text = match edit_to_recheck(m, cached.as_ref(), window, now()) {
+            Some(text) => text,
+            None => return,
+        };
+        // Quotes of the message should show what it says now.
+        if self.cacheable(m) {
+            let text = if self.cfg.cache_text {
+                Some(text.clone())
+            } else {
+                None
+            };
+            if let Err(err) = self.cache_message(m, text) {
+                error!("cache edited message {}: {}", m.id, err);
+            }
+        }
+
+        info!("message {} is edited, check it again", m.id);
+        self.check_message(m).await;
+    }
+
+    /// Check whether the message is worth caching, trivial messages can't be
+    /// flagged.
+    fn cacheable(&self, m: &Message) -> bool {
+        let trivial = match &m.kind {
+            MessageKind::Text { data, .. } => data.chars().count() < self.cfg.min_cached_len,
+            _ => false,
+        };
+        if trivial || self.cfg.uncached_kinds.contains(&content_kind(m)) {
+            debug!("message {} is trivial, skip caching", m.id);
+            return false;
         }
+        true
+    }
+
+    /// Cache the main group message with the hash of its text, `text` is
+    /// what's kept for quoting.
+    fn cache_message(&self, m: &Message, text: Option<String>) -> Result<()> {
+        let cached = cache::CachedMessage {
+            id: m.id,
+            text,
+            hash: message_text(m).map(|text| cache::text_hash(text)),
+        };
+        self.cache.borrow_mut().set(m.date, full_name(&m.from), cached)
+    }
+
+    /// Run automatic checks of main group messages, new or edited.
+    async fn check_message(&self, m: &Message) {
+        self.check_spam_wave(m).await;
+        self.check_filters(m).await;
+        self.check_spam_score(m).await;
+        self.check_scripts(m).await;
+        if matches!(
+            content_kind(m),
+            config::ContentKind::Sticker | config::ContentKind::Animation
+        ) {
+            self.check_stickers(m).await;
+        }
+    }
+
+    // Remember handled commands so that edits of them can be told apart
+    // from new commands.
+    fn track_command(&self, m: &Message, text: String) {
+        let mut handled = self.handled_commands.borrow_mut();
+        if handled.len() > HANDLED_COMMANDS_PRUNE_THRESHOLD {
+            let now = now();
+            handled.retain(|_, (_, time)| now - *time < EDIT_WINDOW);
+        }
+        handled.insert((m.chat.id(), m.id), (text, m.date));
     }
 
     pub async fn handle_message(&self, m: &Message) -> Result<()> {
+        if self.banned_command(m)? {
+            return Ok(());
+        }
+
+        if let Some(cmd) = self.command_for_me(m) {
+            // Anyone may start an appeal from the button under alerts.
+            if let (Some(original), MessageChat::Private(_)) =
+                (appeal_start(&cmd), &m.chat)
+            {
+                {
+                    let now = now();
+                    let mut appeals = self.appeals.borrow_mut();
+                    appeals.retain(|_, (t, _)| now - *t < APPEAL_WINDOW);
+                    appeals.insert(m.from.id, (now, original));
+                }
+                self.api.send(m.text_reply(self.cfg.appeal_text.as_str())).await?;
+                return Ok(());
+            }
+            if self.cfg.edited_commands == config::EditedCommands::Process {
+                self.track_command(m, format!("{} {}", cmd.name, cmd.rest));
+            }
+            return self.handle_command(m, &cmd).await;
+        }
+
         match m.chat {
             MessageChat::Private(_) => {
+                let started = self.appeals.borrow_mut().remove(&m.from.id);
+                if let Some(started) = started.filter(|(t, _)| now() - t < APPEAL_WINDOW) {
+                    return self.post_appeal(m, started).await;
+                }
                 if m.forward.is_none() {
                     warn!("Message is not forwarded to bot, ignore this message");
                     return Ok(());
@@ -151,93 +1091,3862 @@ impl API {
 
                 self.ask_admin(m).await?;
             }
-            MessageChat::Group(_) | MessageChat::Supergroup(_) => {
-                // Cache message that send to main group.
-                if m.chat.id() == ChatId::from(self.cfg.main_group) {
-                    let mut sender = m.from.first_name.clone();
-                    if m.from.last_name.is_some() {
-                        sender.push_str(" ");
-                        sender.push_str(&m.from.last_name.clone().unwrap());
+            // Cache message that send to main group.
+            MessageChat::Group(_) | MessageChat::Supergroup(_)
+                if m.chat.id() == ChatId::from(self.cfg.main_group) =>
+            {
+                if let Some(cmd) = parse_command(&m.kind) {
+                    if cmd.name == "ot" && self.addressed_to_me(&cmd) {
+                        return self.quick_flag(m).await;
                     }
-                    self.cache.borrow_mut().set(m.date, sender, m.id);
                 }
-            }
-            _ => {}
-        }
 
+                if let (MessageKind::NewChatMembers { data }, Some(scorer)) =
+                    (&m.kind, &self.spam_scorer)
+                {
+                    for user in data {
+                        scorer.borrow_mut().join(user.id, now());
+                    }
+                }
 
-        Ok(())
-    }
+                let text = if self.cfg.cache_text {
+                    self.album_text(m)
+                } else {
+                    None
+                };
+                if self.cacheable(m) {
+                    if let Some(text) = &text {
+                        self.record_history(m.id, m.date, text, false);
+                    }
+                    // Caching is best-effort, the message just can't be flagged.
+                    match self.cache_message(m, text) {
+                        Ok(_) => self.stats.borrow_mut().messages_cached += 1,
+                        Err(err) => error!("cache message {}: {}", m.id, err),
+                    }
+                }
 
-    pub async fn handle_callback(&self, c: &CallbackQuery) -> Result<()> {
-        if c.data.is_none() {
-            debug!("callback query {:?} data is empty, ignore", c.id);
-            return Ok(());
+                self.check_message(m).await;
+            }
+            _ => {}
         }
 
-        match Callback::from_string(c.data.as_ref().unwrap())? {
-            Callback::Offtopic { id } => {
-                self.send_ot_alert(id).await?;
-                self.api.send(c.acknowledge()).await?;
-            }
+        if matches!(m.chat, MessageChat::Group(_) | MessageChat::Supergroup(_))
+            && self.cfg.mention_action != config::MentionAction::Ignore
+            && self.mentions_me(m)
+        {
+            self.reply_mention(m).await?;
         }
 
         Ok(())
     }
 
-    pub async fn ask_admin(&self, m: &Message) -> Result<()> {
-        // Check if user is an admin.
-        if !self.admins.contains(&m.from.id) {
-            warn!(
-                "User {}({}) is not an admin",
-                &m.from.first_name, &m.from.id
-            );
+    /// Screen the request to join main group by `join_requests`, requests
+    /// not decided automatically are posted to admin group.
+    pub async fn handle_join_request(&self, r: &ChatJoinRequest) -> Result<()> {
+        let cfg = match &self.cfg.join_requests {
+            Some(cfg) if r.chat.id == self.cfg.main_group => cfg,
+            _ => {
+                debug!("join request of user {} to chat {} is not screened", r.from.id, r.chat.id);
+                return Ok(());
+            }
+        };
+        let main = ChatId::from(self.cfg.main_group);
+        let banned = self.cache.borrow().is_banned(r.from.id)?;
+
+        if banned && cfg.decline_banned {
+            info!("decline join request of banned user {}", r.from.id);
+            self.api.send(DeclineChatJoinRequest::new(main, r.from.id)).await?;
+            self.push_audit(format!(
+                "已自动拒绝曾被封禁的 {}（{}）的入群申请",
+                self.mention(&r.from),
+                r.from.id
+            ))
+            .await;
             return Ok(());
         }
-
-        let mut msg = m.text_reply(
-            format!("该消息存在什么问题？")
-        );
-
-        let oid = self.get_original_message_id(m);
-        if oid.is_none() {
-            return Err(anyhow!("message id not found"));
+        let young = cfg.max_user_id > 0 && Integer::from(r.from.id) > cfg.max_user_id;
+        if cfg.auto_approve && !banned && !young {
+            info!("approve join request of user {}", r.from.id);
+            self.api.send(ApproveChatJoinRequest::new(main, r.from.id)).await?;
+            self.push_audit(format!(
+                "已自动批准 {}（{}）的入群申请",
+                self.mention(&r.from),
+                r.from.id
+            ))
+            .await;
+            return Ok(());
         }
 
+        let mut text = format!("{}（{}）申请加入大群", self.mention(&r.from), r.from.id);
+        if banned {
+            text.push_str("，该用户曾被封禁");
+        } else if young {
+            text.push_str("，该账号可能注册不久");
+        }
+        if let Some(bio) = &r.bio {
+            text.push_str(&format!("\n简介：{}", escape_markdown(bio)));
+        }
+        let decision = |approved| Callback::JoinDecision {
+            user: r.from.id,
+            approved,
+        };
         let mut ikm = InlineKeyboardMarkup::new();
         ikm.add_row(vec![
-            InlineKeyboardButton::callback("离题", Callback::Offtopic { id: oid.unwrap() }.to_string()?),
+            InlineKeyboardButton::callback("批准", decision(true).encode(self.cfg.callback_format)?),
+            InlineKeyboardButton::callback("拒绝", decision(false).encode(self.cfg.callback_format)?),
         ]);
-
-        msg.reply_markup(ikm);
+        let mut msg = SendMessage::new(ChatId::from(self.cfg.admin_group), text);
         msg.parse_mode(ParseMode::Markdown);
-
+        msg.reply_markup(ikm);
         self.api.send(msg).await?;
-
         Ok(())
     }
 
-    pub async fn send_ot_alert(&self, original_message_id: MessageId) -> Result<()> {
-        let mut msg = SendMessage::new(
-            ChatId::from(self.cfg.main_group),
-            format!(r#"
-           请勿进行离题讨论，#archlinux-cn 仅用于 archlinux 相关话题讨论，无关主题请前往 OT 群
-            "#),
+    /// Post the appeal sent in private chat to `appeal_queue`, or admin
+    /// group if it's not set.
+    async fn post_appeal(
+        &self,
+        m: &Message,
+        started: (Integer, Option<MessageId>),
+    ) -> Result<()> {
+        let text = match message_text(m) {
+            Some(text) => text,
+            None => {
+                // Let them try again with text.
+                self.appeals.borrow_mut().insert(m.from.id, started);
+                self.api.send(m.text_reply("请用文字发送申诉内容")).await?;
+                return Ok(());
+            }
+        };
+        info!("user {} appealed", m.from.id);
+        // Appeals are kept for daily summaries even without a queue.
+        let id = self.cache.borrow().appeal_id()?;
+        let mut appeal = cache::Appeal {
+            user: m.from.id,
+            user_name: self.mention(&m.from),
+            text: text.to_string(),
+            time: m.date,
+            message: started.1,
+            record: None,
+            status: cache::AppealStatus::Open,
+        };
+        if let Some(queue) = self.cfg.appeal_queue {
+            let (text, ikm) = self.appeal_record(id, &appeal)?;
+            let mut msg = SendMessage::new(ChatId::from(queue), text);
+            msg.parse_mode(ParseMode::Markdown);
+            if let Some(ikm) = ikm {
+                msg.reply_markup(ikm);
+            }
+            appeal.record = Some(match self.api.send(msg).await? {
+                MessageOrChannelPost::Message(record) => (record.chat.id(), record.id),
+                MessageOrChannelPost::ChannelPost(record) => (record.chat.id.into(), record.id),
+            });
+            self.cache.borrow_mut().set_appeal(id, &appeal)?;
+            self.show_appeal(&appeal).await;
+
+            self.api.send(m.text_reply("申诉已提交，请耐心等待管理员处理")).await?;
+            return Ok(());
+        }
+        self.cache.borrow_mut().set_appeal(id, &appeal)?;
+        self.push_audit(format!(
+            "收到 {}（{}）的申诉：\n{}",
+            self.mention(&m.from),
+            m.from.id,
+            escape_markdown(text)
+        ))
+        .await;
+        self.api.send(m.text_reply("申诉已提交，请耐心等待管理员处理")).await?;
+        Ok(())
+    }
+
+    /// Returns text of the message, items of albums share the caption of
+    /// the album if `album_caption` is set.
+    fn album_text(&self, m: &Message) -> Option<String> {
+        let group = match media_group(m) {
+            Some(group) if self.cfg.album_caption => group,
+            _ => return message_text(m).cloned(),
+        };
+        let mut albums = self.albums.borrow_mut();
+        match message_text(m) {
+            Some(caption) => {
+                let now = now();
+                albums.retain(|_, (time, _)| now - *time < ALBUM_TTL);
+                albums.insert(group.clone(), (now, caption.clone()));
+                Some(caption.clone())
+            }
+            None => albums.get(group).map(|(_, caption)| caption.clone()),
+        }
+    }
+
+    fn mentions_me(&self, m: &Message) -> bool {
+        let (data, entities) = match &m.kind {
+            MessageKind::Text { data, entities } => (data, entities),
+            _ => return false,
+        };
+        let username = self.me.username.as_ref().map(|name| format!("@{}", name));
+        entities.iter().any(|e| match &e.kind {
+            MessageEntityKind::TextMention(user) => user.id == self.me.id,
+            MessageEntityKind::Mention => match &username {
+                Some(username) => entity_text(data, e).eq_ignore_ascii_case(username),
+                None => false,
+            },
+            _ => false,
+        })
+    }
+
+    async fn reply_mention(&self, m: &Message) -> Result<()> {
+        let text = if self.cfg.mention_action == config::MentionAction::Help
+            && self.is_admin(&m.from).await
+        {
+            let usages: Vec<String> = command::COMMANDS.iter().map(|spec| spec.usage()).collect();
+            format!("可用命令：\n{}", usages.join("\n"))
+        } else if self.cfg.mention_action == config::MentionAction::Help {
+            match self.cache.borrow().rules()? {
+                Some(rules) => format!("{}\n\n群规：\n{}", self.cfg.mention_text, rules.text),
+                None => self.cfg.mention_text.clone(),
+            }
+        } else {
+            self.cfg.mention_text.clone()
+        };
+        self.api.send(m.text_reply(text)).await?;
+        Ok(())
+    }
+
+    pub async fn handle_command(&self, m: &Message, cmd: &Command) -> Result<()> {
+        if !self.is_admin(&m.from).await {
+            warn!(
+                "User {}({}) is not an admin, ignore command {}",
+                &m.from.first_name, &m.from.id, &cmd.name
+            );
+            return Ok(());
+        }
+
+        let cmd = &match self.reply_target(m, cmd).await {
+            Ok(cmd) => cmd,
+            Err(err) => {
+                self.api.send(m.text_reply(err.to_string())).await?;
+                return Ok(());
+            }
+        };
+
+        // Commands from phones are easy to send by accident.
+        if let MessageChat::Private(_) = m.chat {
+            if cmd.name == "confirm" {
+                return self.confirm_command(m, cmd).await;
+            }
+            if self.cfg.confirm_commands.contains(&cmd.name) {
+                let code = confirm_code();
+                let text = format!(
+                    "该命令需要确认，请在 {} 秒内发送 /confirm {}",
+                    self.cfg.confirm_timeout, code
+                );
+                self.pending_commands
+                    .borrow_mut()
+                    .insert(m.from.id, (cmd.clone(), code, now()));
+                self.api.send(m.text_reply(text)).await?;
+                return Ok(());
+            }
+        }
+
+        self.run_command(m, cmd).await
+    }
+
+    /// Fill in the user id left out of commands taking `reply_target` from
+    /// the replied message, either a forward from the user or a message
+    /// sent by them. Errors are hints for the admin, including targets
+    /// that are deleted accounts.
+    async fn reply_target(&self, m: &Message, cmd: &Command) -> Result<Command> {
+        let spec = match command::find(&cmd.name) {
+            Some(spec) if spec.reply_target => spec,
+            _ => return Ok(cmd.clone()),
+        };
+        if let Ok(args) = spec.parse(&cmd.rest) {
+            if let Some(Value::UserId(user)) = args.first() {
+                // Unknown users are left to the command.
+                let member = GetChatMember::new(ChatId::from(self.cfg.main_group), *user);
+                if let Ok(member) = self.api.send(member).await {
+                    if is_deleted_account(&member.user) {
+                        return Err(anyhow!("用户 {} 已注销账号，无需处理", user));
+                    }
+                }
+            }
+            return Ok(cmd.clone());
+        }
+
+        let user = replied_user(m, self.me.id, spec)?;
+        Ok(with_target(cmd, user))
+    }
+
+    /// Run the command waiting for the code in `/confirm`.
+    pub async fn confirm_command(&self, m: &Message, confirm: &Command) -> Result<()> {
+        // Codes are single use, guessing wrong drops the command as well.
+        let pending = self.pending_commands.borrow_mut().remove(&m.from.id);
+        let text = match pending {
+            None => "没有待确认的命令",
+            Some((_, code, _)) if code != confirm.rest.trim() => "确认码错误，请重新发送命令",
+            Some((_, _, time)) if now() - time >= self.cfg.confirm_timeout as Integer => {
+                "确认已过期，请重新发送命令"
+            }
+            Some((cmd, _, _)) => {
+                info!("user {} confirmed command {}", m.from.id, &cmd.name);
+                return self.run_command(m, &cmd).await;
+            }
+        };
+        self.api.send(m.text_reply(text)).await?;
+        Ok(())
+    }
+
+    /// Run the command from an authorized admin, replying to `m`.
+    pub async fn run_command<M: CanReplySendMessage + ToSourceChat>(&self, m: &M, cmd: &Command) -> Result<()> {
+        let spec = match command::find(&cmd.name) {
+            Some(spec) => spec,
+            None => {
+                debug!("unknown command {}, ignore", &cmd.name);
+                return Ok(());
+            }
+        };
+        let args = match spec.parse(&cmd.rest) {
+            Ok(args) => args,
+            Err(err) => {
+                self.api
+                    .send(m.text_reply(format!("{}\n用法：{}", err, spec.usage())))
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        match spec.name {
+            "stats" => self.send_stats(m, &args).await?,
+            "admins" => self.send_admins(m).await?,
+            "menu" => {
+                let mut msg = m.text_reply("快捷操作：");
+                msg.reply_markup(self.menu_keyboard()?);
+                self.api.send(msg).await?;
+            }
+            "alerts" => self.send_open_flags(m).await?,
+            "history" => {
+                let id = match args.first() {
+                    Some(Value::Count(id)) => MessageId::from(*id as Integer),
+                    _ => return Err(anyhow!("message id is missing")),
+                };
+                let history = self.cache.borrow().history(id)?;
+                let text = if history.is_empty() {
+                    format!("没有消息 {} 的编辑记录", id)
+                } else {
+                    let mut text = format!("消息 {} 的编辑记录：", id);
+                    for (i, (time, t)) in history.iter().enumerate() {
+                        let label = if i == 0 { "原文" } else { "编辑" };
+                        text.push_str(&format!("\n{} {}：{}", format_time(*time), label, t));
+                    }
+                    text
+                };
+                self.api.send(m.text_reply(text)).await?;
+            }
+            "loglevel" => {
+                let id = match args.first() {
+                    Some(Value::Text(id)) => id,
+                    _ => return Err(anyhow!("reason is missing")),
+                };
+                let text = match (self.cfg.reason(id), args.get(1)) {
+                    (None, _) => format!("{} 不是理由", id),
+                    (Some(_), None) => match self.reason_levels.borrow().get(id) {
+                        Some(level) => format!("理由 {} 的日志级别为 {}", id, level.name()),
+                        None => format!("理由 {} 的日志级别跟随全局设置", id),
+                    },
+                    (Some(_), Some(Value::Text(level))) if level == "default" => {
+                        self.reason_levels.borrow_mut().remove(id);
+                        format!("理由 {} 的日志级别已恢复为跟随全局设置", id)
+                    }
+                    (Some(_), Some(Value::Text(level))) => match config::LogLevel::parse(level) {
+                        Some(level) => {
+                            self.reason_levels.borrow_mut().insert(id.clone(), level);
+                            format!("理由 {} 的日志级别已设为 {}", id, level.name())
+                        }
+                        None => "级别只能是 off、error、warn、info、debug、trace 或 default".to_string(),
+                    },
+                    (Some(_), Some(_)) => "级别只能是 off、error、warn、info、debug、trace 或 default".to_string(),
+                };
+                self.api.send(m.text_reply(text)).await?;
+            }
+            "resolve" => {
+                let id = match args.first() {
+                    Some(Value::Count(id)) => *id,
+                    _ => return Err(anyhow!("flag id is missing")),
+                };
+                let text = if self.cache.borrow_mut().resolve_flag(id, now())? {
+                    self.fire_hooks(config::Event::Resolve, json!({ "flag": id }));
+                    format!("已将 {} 标记为已处理", id)
+                } else {
+                    format!("{} 不存在或已处理", id)
+                };
+                self.api.send(m.text_reply(text)).await?;
+            }
+            "forgive" => {
+                let user = match args.first() {
+                    Some(Value::UserId(user)) => *user,
+                    _ => return Err(anyhow!("user id is missing")),
+                };
+                let text = self.forgive(user).await?;
+                self.api.send(m.text_reply(text.as_str())).await?;
+                // Leave a trace in admin group when forgiven from elsewhere.
+                if m.to_source_chat() != ChatId::from(self.cfg.admin_group) {
+                    let mut msg = SendMessage::new(ChatId::from(self.cfg.admin_group), text);
+                    if !self.cfg.audit_notify {
+                        msg.disable_notification();
+                    }
+                    if let Err(err) = self.api.send(msg).await {
+                        error!("send audit of forgiving user {}: {}", user, err);
+                    }
+                }
+            }
+            "whois" => {
+                let user = match args.first() {
+                    Some(Value::UserId(user)) => *user,
+                    _ => return Err(anyhow!("user id is missing")),
+                };
+                let text = self.whois(user)?;
+                self.api.send(m.text_reply(text.as_str())).await?;
+            }
+            "mute" => {
+                let (user, secs) = match (args.first(), args.get(1)) {
+                    (Some(Value::UserId(user)), Some(Value::Duration(secs))) => (*user, *secs),
+                    _ => return Err(anyhow!("user id or duration is missing")),
+                };
+                let text = self.mute(user, secs).await?;
+                self.api.send(m.text_reply(text.as_str())).await?;
+            }
+            "syncrules" => {
+                let text = self.sync_rules().await?;
+                self.api.send(m.text_reply(text.as_str())).await?;
+            }
+            "testfilter" => {
+                let text = match args.first() {
+                    Some(Value::Text(text)) => text,
+                    _ => return Err(anyhow!("text is missing")),
+                };
+                let text = if self.cfg.normalize_text { normalize(text) } else { text.clone() };
+                let matched: Vec<_> = self
+                    .cfg
+                    .filters
+                    .iter()
+                    .filter(|f| filter::matches(f, &text))
+                    .map(|f| format!("{}（{}）", f.keyword, if f.fuzzy { "模糊" } else { "精确" }))
+                    .collect();
+                let mut reply = if matched.is_empty() {
+                    "未命中任何关键词".to_string()
+                } else {
+                    format!("命中关键词：{}", matched.join("，"))
+                };
+                reply.push_str(&format!("\n模糊匹配文本：{}", filter::fold(&text)));
+                self.api.send(m.text_reply(reply)).await?;
+            }
+            "deadletter" => self.send_dead_letters(m).await?,
+            "retry_deadletter" => {
+                let (total, done) = self.retry_dead_letters().await?;
+                self.api
+                    .send(m.text_reply(format!("重试 {} 条，成功 {} 条", total, done)))
+                    .await?;
+            }
+            _ => debug!("command {} is not implemented, ignore", spec.name),
+        }
+
+        Ok(())
+    }
+
+    pub async fn handle_callback(&self, c: &CallbackQuery) -> Result<()> {
+        if c.data.is_none() {
+            debug!("callback query {:?} data is empty, ignore", c.id);
+            return Ok(());
+        }
+
+        self.stats.borrow_mut().callbacks_handled += 1;
+
+        let callback = Callback::decode(c.data.as_ref().unwrap())?;
+        // Reject presses flooding the handler before asking telegram anything.
+        let presses = if self.admins.borrow().contains(&c.from.id) {
+            &self.admin_presses
+        } else {
+            &self.presses
+        };
+        let limited = presses.borrow_mut().hit((c.from.id, callback.kind()), now());
+        if let Err(secs) = limited {
+            debug!("user {} pressed {} too often", c.from.id, callback.kind());
+            let toast = self.cfg.cooldown_text.replace("{secs}", &secs.max(1).to_string());
+            self.api.send(c.answer(toast)).await?;
+            return Ok(());
+        }
+
+        if self.cfg.callback_admin_check == config::CallbackAdminCheck::Recheck
+            && !self.still_admin(&c.from).await
+        {
+            warn!(
+                "User {}({}) is not an admin anymore, ignore callback {:?}",
+                &c.from.first_name, &c.from.id, c.id
+            );
+            self.api.send(c.answer("你已不是管理员，无法处理")).await?;
+            return Ok(());
+        }
+
+        match callback {
+            Callback::Reason { id, reason } => {
+                if !self.is_open_prompt(c, id)? {
+                    self.api.send(c.answer("该提示已失效")).await?;
+                    return Err(anyhow!("callback {:?} is not from an open prompt", c.id));
+                }
+                if !self.original_exists(c) {
+                    warn!("original message {} of callback {:?} has expired", id, c.id);
+                    self.api.send(c.answer("原消息缓存已过期，无法处理")).await?;
+                    return Ok(());
+                }
+                let reason = match self.cfg.reason(&reason) {
+                    Some(reason) => reason,
+                    None => {
+                        self.api.send(c.answer("该原因已不存在")).await?;
+                        return Err(anyhow!("reason {} not found", reason));
+                    }
+                };
+
+                let large = self.needs_confirm(reason).await?;
+                if large || reason.requires_confirm {
+                    let prompt = match c.message.as_ref() {
+                        Some(MessageOrChannelPost::Message(prompt)) => prompt,
+                        _ => return Err(anyhow!("prompt message not found")),
+                    };
+
+                    let data = Callback::ConfirmReason {
+                        id,
+                        reason: reason.id.clone(),
+                    }
+                    .encode(self.cfg.callback_format)?;
+                    let (label, toast) = if large {
+                        ("确认发送到大群？".to_string(), "该群成员较多，请确认后发送")
+                    } else {
+                        (format!("确认：{}", reason.label), "该原因需要确认后发送")
+                    };
+                    let mut ikm = InlineKeyboardMarkup::new();
+                    ikm.add_row(vec![InlineKeyboardButton::callback(label, data)]);
+
+                    self.api
+                        .send(EditMessageReplyMarkup::new(prompt.chat.id(), prompt.id, Some(ikm)))
+                        .await?;
+                    self.confirms
+                        .borrow_mut()
+                        .insert((prompt.chat.id(), prompt.id), now());
+                    self.api.send(c.answer(toast)).await?;
+                    return Ok(());
+                }
+
+                self.flag(c, id, reason).await?;
+            }
+            Callback::ConfirmReason { id, reason } => {
+                if !self.is_open_prompt(c, id)? {
+                    self.api.send(c.answer("该提示已失效")).await?;
+                    return Err(anyhow!("callback {:?} is not from an open prompt", c.id));
+                }
+                if !self.original_exists(c) {
+                    warn!("original message {} of callback {:?} has expired", id, c.id);
+                    self.api.send(c.answer("原消息缓存已过期，无法处理")).await?;
+                    return Ok(());
+                }
+                let reason = match self.cfg.reason(&reason) {
+                    Some(reason) => reason,
+                    None => {
+                        self.api.send(c.answer("该原因已不存在")).await?;
+                        return Err(anyhow!("reason {} not found", reason));
+                    }
+                };
+
+                // Confirmations are dropped on restart and expire after
+                // confirm_timeout, admins need to choose the reason again.
+                if let Some(MessageOrChannelPost::Message(prompt)) = c.message.as_ref() {
+                    let shown = self.confirms.borrow_mut().remove(&(prompt.chat.id(), prompt.id));
+                    if shown.is_none_or(|t| now() - t >= self.cfg.confirm_timeout as Integer) {
+                        let ikm = self.reasons_keyboard(forwarded_message(c), id)?;
+                        self.api
+                            .send(EditMessageReplyMarkup::new(prompt.chat.id(), prompt.id, Some(ikm)))
+                            .await?;
+                        self.api.send(c.answer("确认已过期，请重新选择")).await?;
+                        return Ok(());
+                    }
+                }
+
+                self.flag(c, id, reason).await?;
+            }
+            Callback::Batch { id, reason } => self.handle_batch(c, id, &reason, false).await?,
+            Callback::ConfirmBatch { id, reason } => {
+                self.handle_batch(c, id, &reason, true).await?
+            }
+            Callback::AdminsPage { group, offset } => {
+                if !self.is_admin(&c.from).await
+                    || (group != self.cfg.main_group && group != self.cfg.admin_group)
+                {
+                    self.api.send(c.answer("无权查看")).await?;
+                    return Ok(());
+                }
+
+                let page = match c.message.as_ref() {
+                    Some(MessageOrChannelPost::Message(page)) => page,
+                    _ => return Err(anyhow!("admins page message not found")),
+                };
+
+                let (text, ikm) = self.admins_page(group, offset).await?;
+                let mut msg = EditMessageText::new(page.chat.id(), page.id, text);
+                msg.parse_mode(ParseMode::Markdown);
+                msg.reply_markup(ikm);
+
+                self.api.send(msg).await?;
+                self.api.send(c.acknowledge()).await?;
+            }
+            Callback::Appeal { id, status } => {
+                if !self.is_admin(&c.from).await {
+                    self.api.send(c.answer("无权操作")).await?;
+                    return Ok(());
+                }
+
+                let appeal = self.cache.borrow().appeal(id)?;
+                let mut appeal = match appeal {
+                    Some(appeal) => appeal,
+                    None => {
+                        self.api.send(c.answer("该申诉已不存在")).await?;
+                        return Err(anyhow!("appeal {} not found", id));
+                    }
+                };
+                if appeal.status == status || appeal.status == cache::AppealStatus::Resolved {
+                    self.api.send(c.answer("该申诉状态已更新")).await?;
+                    return Ok(());
+                }
+                appeal.status = status;
+                self.cache.borrow_mut().set_appeal(id, &appeal)?;
+                info!("appeal {} is marked {:?} by {}", id, status, c.from.id);
+
+                if let Some((chat, record)) = appeal.record {
+                    let (text, ikm) = self.appeal_record(id, &appeal)?;
+                    let mut msg = EditMessageText::new(chat, record, text);
+                    msg.parse_mode(ParseMode::Markdown);
+                    if let Some(ikm) = ikm {
+                        msg.reply_markup(ikm);
+                    }
+                    self.api.send(msg).await?;
+                }
+                self.show_appeal(&appeal).await;
+
+                // Users may have blocked the bot since.
+                let notice = SendMessage::new(
+                    appeal.user,
+                    format!("你的申诉{}", appeal_label(status)),
+                );
+                if let Err(err) = self.api.send(notice).await {
+                    warn!("notify user {} of appeal {}: {}", appeal.user, id, err);
+                }
+                self.api
+                    .send(c.answer(format!("已标记为{}", appeal_label(status))))
+                    .await?;
+            }
+            Callback::JoinDecision { user, approved } => {
+                if !self.is_admin(&c.from).await {
+                    self.api.send(c.answer("无权操作")).await?;
+                    return Ok(());
+                }
+
+                let main = ChatId::from(self.cfg.main_group);
+                let (result, label) = if approved {
+                    (self.api.send(ApproveChatJoinRequest::new(main, user)).await, "批准")
+                } else {
+                    (self.api.send(DeclineChatJoinRequest::new(main, user)).await, "拒绝")
+                };
+                // The request may have been withdrawn or decided elsewhere.
+                if let Err(err) = result {
+                    self.api.send(c.answer(format!("处理失败：{}", err))).await?;
+                    return Err(anyhow!("decide join request of user {}: {}", user, err));
+                }
+                info!("join request of user {} is decided by {}: {}", user, c.from.id, approved);
+
+                if let Some(MessageOrChannelPost::Message(record)) = c.message.as_ref() {
+                    let text = format!(
+                        "{}\n\n已由 {} {}",
+                        message_text(record).map_or("", |text| text.as_str()),
+                        full_name(&c.from),
+                        label
+                    );
+                    let edit = EditMessageText::new(record.chat.id(), record.id, text);
+                    if let Err(err) = self.api.send(edit).await {
+                        warn!("close join request of user {}: {}", user, err);
+                    }
+                }
+                self.api.send(c.answer(format!("已{}", label))).await?;
+            }
+            Callback::Menu { action } => {
+                if !self.is_admin(&c.from).await {
+                    self.api.send(c.answer("无权操作")).await?;
+                    return Ok(());
+                }
+
+                let menu = match c.message.as_ref() {
+                    Some(MessageOrChannelPost::Message(menu)) => menu,
+                    _ => return Err(anyhow!("menu message not found")),
+                };
+
+                match action {
+                    MenuAction::Pause => {
+                        let paused = !self.paused.get();
+                        self.paused.set(paused);
+                        info!("alerts are paused: {}, by {}", paused, c.from.id);
+
+                        self.api
+                            .send(EditMessageReplyMarkup::new(
+                                menu.chat.id(),
+                                menu.id,
+                                Some(self.menu_keyboard()?),
+                            ))
+                            .await?;
+                        let toast = if paused { "已暂停发送提醒" } else { "已恢复发送提醒" };
+                        self.api.send(c.answer(toast)).await?;
+                    }
+                    MenuAction::Stats => {
+                        self.api.send(c.acknowledge()).await?;
+                        self.send_stats(menu, &[]).await?;
+                    }
+                    MenuAction::ReloadAdmins => {
+                        let count = self.reload_admins().await?;
+                        self.api
+                            .send(c.answer(format!("已刷新，共 {} 位管理员", count)))
+                            .await?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle a reason chosen in a batch prompt, confirmed reasons are
+    /// applied at once.
+    pub async fn handle_batch(
+        &self,
+        c: &CallbackQuery,
+        batch: u64,
+        reason: &str,
+        confirmed: bool,
+    ) -> Result<()> {
+        let prompt = match c.message.as_ref() {
+            Some(MessageOrChannelPost::Message(prompt)) => prompt,
+            _ => return Err(anyhow!("prompt message not found")),
+        };
+        let b = self.cache.borrow().batch(batch)?;
+        let b = match b.filter(|b| b.prompt == (prompt.chat.id(), prompt.id)) {
+            Some(b) => b,
+            None => {
+                self.api.send(c.answer("该提示已失效")).await?;
+                return Err(anyhow!("callback {:?} is not from an open batch", c.id));
+            }
+        };
+        let reason = match self.cfg.reason(reason) {
+            Some(reason) => reason,
+            None => {
+                self.api.send(c.answer("该原因已不存在")).await?;
+                return Err(anyhow!("reason {} not found", reason));
+            }
+        };
+
+        if confirmed {
+            let shown = self.confirms.borrow_mut().remove(&(prompt.chat.id(), prompt.id));
+            if shown.is_none_or(|t| now() - t >= self.cfg.confirm_timeout as Integer) {
+                let ikm = self.batch_keyboard(batch, &b.items, c.from.language_code.as_deref())?;
+                self.api
+                    .send(EditMessageReplyMarkup::new(prompt.chat.id(), prompt.id, Some(ikm)))
+                    .await?;
+                self.api.send(c.answer("确认已过期，请重新选择")).await?;
+                return Ok(());
+            }
+        } else {
+            let large = self.needs_confirm(reason).await?;
+            if large || reason.requires_confirm {
+                let data = Callback::ConfirmBatch {
+                    id: batch,
+                    reason: reason.id.clone(),
+                }
+                .encode(self.cfg.callback_format)?;
+                let label = if large {
+                    format!("确认将 {} 条消息发送到大群？", b.items.len())
+                } else {
+                    format!("确认：{}（{} 条）", reason.label, b.items.len())
+                };
+                let mut ikm = InlineKeyboardMarkup::new();
+                ikm.add_row(vec![InlineKeyboardButton::callback(label, data)]);
+
+                self.api
+                    .send(EditMessageReplyMarkup::new(prompt.chat.id(), prompt.id, Some(ikm)))
+                    .await?;
+                self.confirms
+                    .borrow_mut()
+                    .insert((prompt.chat.id(), prompt.id), now());
+                self.api.send(c.answer("批量处理需要确认后发送")).await?;
+                return Ok(());
+            }
+        }
+
+        self.flag_batch(c, batch, reason).await
+    }
+
+    /// Check that the original message of the callback's prompt is still
+    /// cached, it could have been pruned while the admin was deciding.
+    pub fn original_exists(&self, c: &CallbackQuery) -> bool {
+        match forwarded_message(c) {
+            Some(m) => self.get_original_message(m).is_some(),
+            // Nothing to check against if the forward is gone.
+            None => true,
+        }
+    }
+
+    /// Check that the callback comes from a prompt issued for the original
+    /// message and not handled yet, since callback data can be crafted.
+    pub fn is_open_prompt(&self, c: &CallbackQuery, id: MessageId) -> Result<bool> {
+        let prompt = match c.message.as_ref() {
+            Some(MessageOrChannelPost::Message(prompt)) => (prompt.chat.id(), prompt.id),
+            _ => return Ok(false),
+        };
+        Ok(self.cache.borrow().prompts(id)?.contains(&prompt))
+    }
+
+    /// Check whether the user is an admin of main group.
+    ///
+    /// Admins are fetched on startup, users promoted since then are checked
+    /// on demand at most once per `admin_check_interval` and remembered.
+    pub async fn is_admin(&self, user: &User) -> bool {
+        if self.admins.borrow().contains(&user.id) {
+            return true;
+        }
+        // Bootstrap admins are always in admins.
+        if self.cfg.excluded_admins.contains(&Integer::from(user.id)) {
+            return false;
+        }
+        if self.admin_checks.borrow_mut().hit(user.id, now()).is_err() {
+            return false;
+        }
+
+        for group in self.cfg.admin_groups() {
+            match self
+                .api
+                .send(GetChatMember::new(ChatId::from(group), user.id))
+                .await
+            {
+                Ok(member)
+                    if matches!(
+                        member.status,
+                        ChatMemberStatus::Creator | ChatMemberStatus::Administrator
+                    ) =>
+                {
+                    info!(
+                        "user {}({}) has been promoted to admin of {}",
+                        &user.first_name, user.id, group
+                    );
+                    self.admins.borrow_mut().insert(user.id);
+                    return true;
+                }
+                Ok(_) => {}
+                Err(err) => warn!("check whether user {} is an admin of {}: {}", user.id, group, err),
+            }
+        }
+        false
+    }
+
+    /// Check with telegram whether the user is still an admin and drop them
+    /// from admins if not, the cached status is used if telegram fails.
+    ///
+    /// Results are reused for ADMIN_CHECK_TTL.
+    pub async fn still_admin(&self, user: &User) -> bool {
+        let now = now();
+        if let Some((admin, time)) = self.admin_rechecks.borrow().get(&user.id) {
+            if now - time < ADMIN_CHECK_TTL {
+                return *admin;
+            }
+        }
+        let admin = self.check_admin(user).await;
+        self.admin_rechecks.borrow_mut().insert(user.id, (admin, now));
+        admin
+    }
+
+    /// Check with telegram whether the user is an admin, see `still_admin`.
+    async fn check_admin(&self, user: &User) -> bool {
+        let id = Integer::from(user.id);
+        if self.cfg.bootstrap_admins.contains(&id) {
+            return true;
+        }
+        if self.cfg.excluded_admins.contains(&id) {
+            return false;
+        }
+        if self.cfg.extra_admins.contains(&id) {
+            return true;
+        }
+
+        for group in self.cfg.admin_groups() {
+            match self
+                .api
+                .send(GetChatMember::new(ChatId::from(group), user.id))
+                .await
+            {
+                Ok(member)
+                    if matches!(
+                        member.status,
+                        ChatMemberStatus::Creator | ChatMemberStatus::Administrator
+                    ) =>
+                {
+                    return true;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    warn!("check whether user {} is an admin of {}: {}", user.id, group, err);
+                    return self.admins.borrow().contains(&user.id);
+                }
+            }
+        }
+        if self.admins.borrow_mut().remove(&user.id) {
+            info!("user {}({}) has been demoted", &user.first_name, user.id);
+        }
+        false
+    }
+
+    /// Post alert of the reason for the original message as decided in the
+    /// callback.
+    pub async fn flag(
+        &self,
+        c: &CallbackQuery,
+        id: MessageId,
+        reason: &config::Reason,
+    ) -> Result<()> {
+        let item = flagged_item(c, id);
+        match self.hold_flag(&c.from, &item, reason).await {
+            Some(Held::Paused) => {
+                self.api.send(c.answer("提醒已暂停，请恢复后再试")).await?;
+                return Ok(());
+            }
+            Some(Held::CoolingDown(secs)) => {
+                let toast = self.cfg.cooldown_text.replace("{secs}", &secs.max(1).to_string());
+                self.api.send(c.answer(toast)).await?;
+                return Ok(());
+            }
+            Some(Held::Suppressed(recorded)) => {
+                match recorded {
+                    Ok(_) => self.api.send(c.answer("该用户刚被提醒过，本次仅记录")).await?,
+                    Err(err) => {
+                        error!("record flag of callback {:?}: {}", c.id, err);
+                        self.api
+                            .send(c.answer(format!("该用户刚被提醒过，但记录失败：{}", err)))
+                            .await?
+                    }
+                };
+                self.send_audit(c, reason, &format!("{}（用户提醒冷却中，未重复提醒）", reason.label))
+                    .await;
+                return Ok(());
+            }
+            None => {}
+        }
+
+        // Answer before posting the alert, telegram clients show an error if
+        // the callback isn't answered in time. Failures are followed up in
+        // the prompt's chat instead.
+        let hidden = matches!(
+            forwarded_message(c).and_then(|m| m.forward.as_ref()),
+            Some(Forward {
+                from: ForwardFrom::ChannelHiddenUser { .. },
+                ..
+            })
+        );
+        if hidden && !self.cfg.hidden_user_text.is_empty() {
+            self.api.send(c.answer(self.cfg.hidden_user_text.as_str())).await?;
+        } else {
+            self.api.send(c.acknowledge()).await?;
+        }
+
+        match self.post_flag(&c.from, &item, reason).await {
+            Ok((_, Err(err))) => {
+                error!("record flag of callback {:?}: {}", c.id, err);
+                self.follow_up(c, &format!("提醒已发送，但记录失败：{}", err)).await;
+            }
+            Ok((Posted::Suppressed, Ok(_))) => return Ok(()),
+            Ok((Posted::Sent, Ok(_))) => {}
+            Err(err) => {
+                self.follow_up(c, &format!("发送提醒失败，稍后将重试：{}", err))
+                    .await;
+                return Err(err);
+            }
+        }
+        self.send_audit(c, reason, &reason.label).await;
+
+        Ok(())
+    }
+
+    /// Key of the alert in `cooldown` by `cooldown_scope`.
+    fn cooldown_key(&self, id: MessageId, admin: UserId, reason: &config::Reason) -> Integer {
+        match self.cfg.cooldown_scope {
+            config::CooldownScope::Message => Integer::from(id),
+            config::CooldownScope::Admin => Integer::from(admin),
+            config::CooldownScope::Group => match self.alert_chat(reason) {
+                Some(chat) => chat,
+                None => Integer::from(id),
+            },
+        }
+    }
+
+    /// Check whether the alert of the flagged message is held back, the
+    /// flag is recorded if it's only suppressed. Returns `None` if the
+    /// alert should be posted with `post_flag`.
+    async fn hold_flag(
+        &self,
+        admin: &User,
+        item: &cache::BatchItem,
+        reason: &config::Reason,
+    ) -> Option<Held> {
+        if self.paused.get() {
+            return Some(Held::Paused);
+        }
+        let key = self.cooldown_key(item.id, admin.id, reason);
+        let limited = self.cooldown.borrow_mut().hit(key, now());
+        if let Err(secs) = limited {
+            self.log_reason(
+                reason,
+                format_args!("alert for message {} is cooling down for {}s", item.id, secs),
+            );
+            return Some(Held::CoolingDown(secs));
+        }
+
+        // Don't pile reminders on the same user, hidden users can't be
+        // tracked and are always reminded.
+        // Deleted accounts can't read reminders anyway.
+        let author = item.author.filter(|_| !item.deleted)?;
+        if self.user_reminders.borrow_mut().hit(author, now()).is_ok() {
+            return None;
+        }
+        self.log_reason(
+            reason,
+            format_args!("user {} has been reminded recently", author),
+        );
+        let recorded = self.record_flag(admin.id, item, reason, true, None);
+        self.fire_hooks(
+            config::Event::Suppressed,
+            json!({
+                "message": item.id,
+                "reason": reason.id,
+                "admin": admin.id,
+                "author": author,
+            }),
+        );
+        self.close_prompts(item.id, admin, &reason.label).await;
+        Some(Held::Suppressed(recorded))
+    }
+
+    /// Post the alert of the flagged message and record the flag. Alerts
+    /// failed to post are buried and returned as errors, failures to record
+    /// are returned along with the outcome.
+    async fn post_flag(
+        &self,
+        admin: &User,
+        item: &cache::BatchItem,
+        reason: &config::Reason,
+    ) -> Result<(Posted, Result<()>)> {
+        let posted = match self.send_ot_alert(item.id, reason).await {
+            Ok(posted) => posted,
+            Err(err) => {
+                self.bury(
+                    Action::Alert {
+                        id: item.id,
+                        reason: reason.id.clone(),
+                    },
+                    &err,
+                );
+                return Err(err);
+            }
+        };
+        if posted == Posted::Suppressed {
+            let recorded = self.record_flag(admin.id, item, reason, true, None);
+            self.close_prompts(item.id, admin, &reason.label).await;
+            return Ok((posted, recorded));
+        }
+        let author = item.author.filter(|_| !item.deleted);
+        let dm = self.dm_author(author, reason).await;
+        let recorded = self.record_flag(admin.id, item, reason, false, dm);
+        self.fire_hooks(
+            config::Event::Alert,
+            json!({
+                "message": item.id,
+                "reason": reason.id,
+                "admin": admin.id,
+                "author": author,
+            }),
+        );
+        self.close_prompts(item.id, admin, &reason.label).await;
+        Ok((posted, recorded))
+    }
+
+    /// Flag the message replied by `/ot` with `default_reason`, or ask the
+    /// admin privately if it's unset.
+    pub async fn quick_flag(&self, m: &Message) -> Result<()> {
+        if !self.is_admin(&m.from).await {
+            warn!(
+                "User {}({}) is not an admin, ignore /ot",
+                &m.from.first_name, &m.from.id
+            );
+            return Ok(());
+        }
+        let target = match m.reply_to_message.as_deref() {
+            Some(MessageOrChannelPost::Message(target)) => target,
+            _ => {
+                self.api.send(m.text_reply("请回复要处理的消息后再使用此命令")).await?;
+                return Ok(());
+            }
+        };
+        // Commands are noise in main group.
+        if let Err(err) = self.api.send(DeleteMessage::new(m.chat.id(), m.id)).await {
+            warn!("delete /ot message {}: {}", m.id, err);
+        }
+
+        // default_reason has been checked while starting.
+        let reason = match self.cfg.default_reason.as_ref().and_then(|id| self.cfg.reason(id)) {
+            Some(reason) => reason,
+            None => {
+                let forward = ForwardMessage::new(target, m.chat.id(), m.from.id);
+                let forwarded = self.api.send(forward).await?;
+                return self.send_prompt(&forwarded).await;
+            }
+        };
+        // Confirmations are only shown in prompts, ask the admin privately
+        // as well.
+        if reason.requires_confirm || self.needs_confirm(reason).await? {
+            let forward = ForwardMessage::new(target, m.chat.id(), m.from.id);
+            let forwarded = self.api.send(forward).await?;
+            return self.send_prompt(&forwarded).await;
+        }
+
+        let item = cache::BatchItem {
+            id: target.id,
+            kind: content_kind(target),
+            author: Some(target.from.id),
+            author_name: full_name(&target.from),
+            deleted: is_deleted_account(&target.from),
+        };
+        let result = match self.hold_flag(&m.from, &item, reason).await {
+            Some(Held::Paused) => "提醒已暂停，请恢复后再试".to_string(),
+            Some(Held::CoolingDown(secs)) => {
+                self.cfg.cooldown_text.replace("{secs}", &secs.max(1).to_string())
+            }
+            Some(Held::Suppressed(recorded)) => {
+                if let Err(err) = recorded {
+                    error!("record flag of message {}: {}", target.id, err);
+                }
+                self.push_audit(format!(
+                    "{} 用 /ot 将 {} 的消息标记为{}（用户提醒冷却中，未重复提醒）",
+                    self.mention(&m.from),
+                    self.mention(&target.from),
+                    reason.label
+                ))
+                .await;
+                "该用户刚被提醒过，本次仅记录".to_string()
+            }
+            None => {
+                let (posted, recorded) = self.post_flag(&m.from, &item, reason).await?;
+                if let Err(err) = recorded {
+                    error!("record flag of message {}: {}", target.id, err);
+                }
+                if posted == Posted::Suppressed {
+                    return Ok(());
+                }
+                self.push_audit(format!(
+                    "{} 用 /ot 将 {} 的消息标记为{}",
+                    self.mention(&m.from),
+                    self.mention(&target.from),
+                    reason.label
+                ))
+                .await;
+                return Ok(());
+            }
+        };
+        // The /ot message is gone, tell the admin privately.
+        if let Err(err) = self.api.send(SendMessage::new(m.from.id, result)).await {
+            warn!("tell admin {} about /ot: {}", m.from.id, err);
+        }
+        Ok(())
+    }
+
+    /// Send `dm_template` of the reason to the author, returns whether it
+    /// was delivered or `None` if there's nothing to send. Authors who
+    /// haven't started the bot or have blocked it can't be reached.
+    async fn dm_author(&self, author: Option<UserId>, reason: &config::Reason) -> Option<bool> {
+        let (author, text) = match (author, &reason.dm_template) {
+            (Some(author), Some(text)) => (author, text),
+            _ => return None,
+        };
+        match self.api.send(SendMessage::new(author, text.as_str())).await {
+            Ok(_) => {
+                self.log_reason(reason, format_args!("sent dm to user {}", author));
+                Some(true)
+            }
+            Err(err) => {
+                self.log_reason(reason, format_args!("user {} is unreachable: {}", author, err));
+                Some(false)
+            }
+        }
+    }
+
+    /// Log details of handling the reason, visible without debug logs if
+    /// the reason is verbose.
+    fn log_reason(&self, reason: &config::Reason, args: std::fmt::Arguments) {
+        let level = match self.reason_levels.borrow().get(&reason.id) {
+            None => Level::Debug,
+            Some(level) if log::LevelFilter::from(*level) < Level::Debug => return,
+            // Raise details to the global level so that the reason can be
+            // traced alone.
+            Some(_) => match log::max_level().to_level() {
+                Some(max) => max.min(Level::Debug),
+                None => return,
+            },
+        };
+        log!(level, "[reason {}] {}", reason.id, args);
+    }
+
+    /// Reply to the callback's prompt for results that come after the
+    /// callback has been answered.
+    pub async fn follow_up(&self, c: &CallbackQuery, text: &str) {
+        let msg = match c.message.as_ref() {
+            Some(MessageOrChannelPost::Message(prompt)) => prompt.text_reply(text),
+            _ => SendMessage::new(ChatId::from(c.from.id), text),
+        };
+        if let Err(err) = self.api.send(msg).await {
+            error!("follow up callback {:?}: {}", c.id, err);
+        }
+    }
+
+    /// Keep the flag for daily summaries.
+    pub fn record_flag(
+        &self,
+        admin: UserId,
+        item: &cache::BatchItem,
+        reason: &config::Reason,
+        suppressed: bool,
+        dm: Option<bool>,
+    ) -> Result<()> {
+        let f = stats::Flag {
+            time: now(),
+            reason: reason.id.clone(),
+            admin,
+            author: item.author,
+            author_name: item.author_name.clone(),
+            suppressed,
+            message: Some(item.id),
+            resolved: None,
+            dm,
+        };
+        self.cache.borrow_mut().push_flag(&f)
+    }
+
+    /// Check whether the alert of the reason will be posted to a group
+    /// large enough to be confirmed first.
+    pub async fn needs_confirm(&self, reason: &config::Reason) -> Result<bool> {
+        if self.cfg.confirm_member_threshold == 0 {
+            return Ok(false);
+        }
+
+        match self.alert_chat(reason) {
+            None => Ok(false),
+            Some(chat) => Ok(self.member_count(chat).await? > self.cfg.confirm_member_threshold),
+        }
+    }
+
+    /// Returns member count of the chat, cached for MEMBER_COUNT_TTL.
+    pub async fn member_count(&self, chat: i64) -> Result<Integer> {
+        let now = now();
+        if let Some((count, time)) = self.member_counts.borrow().get(&chat) {
+            if now - time < MEMBER_COUNT_TTL {
+                return Ok(*count);
+            }
+        }
+
+        let count = self
+            .api
+            .send(GetChatMembersCount::new(ChatId::from(chat)))
+            .await?;
+        self.member_counts.borrow_mut().insert(chat, (count, now));
+
+        Ok(count)
+    }
+
+    /// Returns title and username of the chat, cached for CHAT_INFO_TTL.
+    ///
+    /// Stale metadata is returned if it can't be refreshed.
+    pub async fn chat_info(&self, chat: i64) -> Result<cache::ChatInfo> {
+        let now = now();
+        let cached = self.cache.borrow().chat_info(chat)?;
+        if let Some(info) = &cached {
+            if now - info.time < CHAT_INFO_TTL {
+                return Ok(info.clone());
+            }
+        }
+
+        let (title, username) = match self.api.send(GetChat::new(ChatId::from(chat))).await {
+            Ok(Chat::Private(user)) => (Some(full_name(&user)), user.username),
+            Ok(Chat::Group(group)) => (Some(group.title), None),
+            Ok(Chat::Supergroup(group)) => (Some(group.title), group.username),
+            Ok(Chat::Channel(channel)) => (Some(channel.title), channel.username),
+            Ok(Chat::Unknown(_)) => (None, None),
+            Err(err) => match cached {
+                Some(info) => {
+                    warn!("refresh chat info of {}: {}, use stale one", chat, err);
+                    return Ok(info);
+                }
+                None => return Err(err.into()),
+            },
+        };
+        let info = cache::ChatInfo {
+            title,
+            username,
+            time: now,
+        };
+        self.cache.borrow_mut().set_chat_info(chat, &info)?;
+
+        Ok(info)
+    }
+
+    /// Build a link to a message in main group, public groups are linked
+    /// by username so that non-members can open it.
+    pub async fn main_message_link(&self, id: MessageId) -> String {
+        match self.chat_info(self.cfg.main_group).await {
+            Ok(cache::ChatInfo {
+                username: Some(username),
+                ..
+            }) => format!("https://t.me/{}/{}", username, id),
+            Ok(_) => message_link(self.cfg.main_group, id),
+            Err(err) => {
+                warn!("get chat info of main group: {}", err);
+                message_link(self.cfg.main_group, id)
+            }
+        }
+    }
+
+    pub async fn ask_admin(&self, m: &Message) -> Result<()> {
+        // Check if user is an admin.
+        if !self.is_admin(&m.from).await {
+            warn!(
+                "User {}({}) is not an admin",
+                &m.from.first_name, &m.from.id
+            );
+            return Ok(());
+        }
+
+        // Flagging own messages is usually a test or a mistake.
+        if let Some(Forward {
+            from: ForwardFrom::User { user },
+            ..
+        }) = &m.forward
+        {
+            if user.id == m.from.id {
+                match self.cfg.self_flag {
+                    config::SelfFlag::Allow => {}
+                    config::SelfFlag::Warn => {
+                        self.api.send(m.text_reply("你正在标记自己的消息")).await?;
+                    }
+                    config::SelfFlag::Block => {
+                        self.api
+                            .send(m.text_reply("你正在标记自己的消息，已忽略"))
+                            .await?;
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        if self.cfg.batch_window > 0 {
+            return self.buffer_forward(m).await;
+        }
+        self.send_prompt(m).await
+    }
+
+    /// Ask the admin about the forwarded message.
+    pub async fn send_prompt(&self, m: &Message) -> Result<()> {
+        // Quote the forwarded message so that admins handling several
+        // forwards at once know which one the prompt is about.
+        let forward = m.forward.as_ref().unwrap();
+        let mut msg = m.text_reply(format!(
+            "{} 于 {} 发送的消息：{}\n该消息存在什么问题？",
+            escape_markdown(&forward_author(&forward.from)),
+            format_time(forward.date),
+            escape_markdown(&summarize(m, self.cfg.normalize_text))
+        ));
+
+        let oid = self.get_original_message_id(m);
+        if oid.is_none() {
+            return Err(anyhow!("message id not found"));
+        }
+
+        let oid = oid.unwrap();
+
+        msg.reply_markup(self.reasons_keyboard(Some(m), oid)?);
+        msg.parse_mode(ParseMode::Markdown);
+
+        if let MessageOrChannelPost::Message(prompt) = self.api.send(msg).await? {
+            let pushed = self
+                .cache
+                .borrow_mut()
+                .push_prompt(oid, (prompt.chat.id(), prompt.id));
+            if let Err(err) = pushed {
+                self.api
+                    .send(m.text_reply(format!("记录提示失败，按钮将无法使用：{}", err)))
+                    .await?;
+                return Err(err);
+            }
+        }
+        self.stats.borrow_mut().prompts_sent += 1;
+
+        Ok(())
+    }
+
+    /// Collect the forward into the admin's batch, the batch is prompted once
+    /// it's full or `batch_window` has passed.
+    pub async fn buffer_forward(&self, m: &Message) -> Result<()> {
+        if self.get_original_message_id(m).is_none() {
+            return Err(anyhow!("message id not found"));
+        }
+
+        let full = {
+            let mut batches = self.batches.borrow_mut();
+            let (_, forwards) = batches.entry(m.from.id).or_insert_with(|| (now(), Vec::new()));
+            forwards.push(m.clone());
+            forwards.len() >= self.cfg.batch_max
+        };
+        if full {
+            self.flush_batch(m.from.id).await?;
+        }
+        Ok(())
+    }
+
+    /// Prompt batches whose window has passed.
+    pub async fn flush_due_batches(&self) {
+        let due: Vec<_> = self
+            .batches
+            .borrow()
+            .iter()
+            .filter(|(_, (t, _))| now() - t >= self.cfg.batch_window as Integer)
+            .map(|(admin, _)| *admin)
+            .collect();
+        for admin in due {
+            if let Err(err) = self.flush_batch(admin).await {
+                error!("send batch prompt of admin {}: {}", admin, err);
+            }
+        }
+    }
+
+    /// Send a single prompt for all forwards collected from the admin.
+    pub async fn flush_batch(&self, admin: UserId) -> Result<()> {
+        let forwards = match self.batches.borrow_mut().remove(&admin) {
+            Some((_, forwards)) => forwards,
+            None => return Ok(()),
+        };
+        if let [m] = forwards.as_slice() {
+            return self.send_prompt(m).await;
+        }
+
+        let mut items = Vec::with_capacity(forwards.len());
+        let mut text = format!("{} 条消息：\n", forwards.len());
+        for m in &forwards {
+            // Messages may be pruned from cache while waiting.
+            let (id, forward) = match (self.get_original_message_id(m), &m.forward) {
+                (Some(id), Some(forward)) => (id, forward),
+                _ => continue,
+            };
+            let (author, deleted) = match &forward.from {
+                ForwardFrom::User { user } => (Some(user.id), is_deleted_account(user)),
+                _ => (None, false),
+            };
+            let author_name = forward_author(&forward.from);
+            text.push_str(&format!(
+                "{}. {} 于 {} 发送：{}\n",
+                items.len() + 1,
+                escape_markdown(&author_name),
+                format_time(forward.date),
+                escape_markdown(&summarize(m, self.cfg.normalize_text))
+            ));
+            items.push(cache::BatchItem {
+                id,
+                kind: content_kind(m),
+                author,
+                author_name,
+                deleted,
+            });
+        }
+        if items.is_empty() {
+            return Err(anyhow!("message ids of batch not found"));
+        }
+        text.push_str("这些消息存在什么问题？");
+
+        let id = self.cache.borrow().batch_id()?;
+        let last = forwards.last().unwrap();
+        let mut msg = last.text_reply(text);
+        msg.reply_markup(self.batch_keyboard(id, &items, last.from.language_code.as_deref())?);
+        msg.parse_mode(ParseMode::Markdown);
+
+        if let MessageOrChannelPost::Message(prompt) = self.api.send(msg).await? {
+            let b = cache::Batch {
+                prompt: (prompt.chat.id(), prompt.id),
+                items,
+            };
+            let set = self.cache.borrow_mut().set_batch(id, &b);
+            if let Err(err) = set {
+                self.api
+                    .send(last.text_reply(format!("记录提示失败，按钮将无法使用：{}", err)))
+                    .await?;
+                return Err(err);
+            }
+        }
+        self.stats.borrow_mut().prompts_sent += 1;
+
+        Ok(())
+    }
+
+    /// Reasons applicable to every message in the batch.
+    pub fn batch_keyboard(
+        &self,
+        batch: u64,
+        items: &[cache::BatchItem],
+        lang: Option<&str>,
+    ) -> Result<InlineKeyboardMarkup> {
+        let reasons: Vec<_> = self
+            .cfg
+            .reasons
+            .iter()
+            .filter(|r| r.kinds.is_empty() || items.iter().all(|i| r.kinds.contains(&i.kind)))
+            .collect();
+
+        let mut ikm = InlineKeyboardMarkup::new();
+        for reasons in reasons.chunks(3) {
+            let mut row = Vec::with_capacity(reasons.len());
+            for reason in reasons {
+                let data = Callback::Batch {
+                    id: batch,
+                    reason: reason.id.clone(),
+                }
+                .encode(self.cfg.callback_format)?;
+                row.push(InlineKeyboardButton::callback(self.button_label(reason, lang), data));
+            }
+            ikm.add_row(row);
+        }
+
+        Ok(ikm)
+    }
+
+    /// Apply the reason to every message in the batch, one by one as if
+    /// they were flagged separately.
+    pub async fn flag_batch(
+        &self,
+        c: &CallbackQuery,
+        batch: u64,
+        reason: &config::Reason,
+    ) -> Result<()> {
+        if self.paused.get() {
+            self.api.send(c.answer("提醒已暂停，请恢复后再试")).await?;
+            return Ok(());
+        }
+        let b = self.cache.borrow_mut().take_batch(batch)?;
+        let b = match b {
+            Some(b) => b,
+            None => {
+                self.api.send(c.answer("该提示已失效")).await?;
+                return Ok(());
+            }
+        };
+        self.api.send(c.acknowledge()).await?;
+
+        let (mut sent, mut suppressed, mut limited, mut failed) = (0, 0, 0, 0);
+        for item in &b.items {
+            match self.hold_flag(&c.from, item, reason).await {
+                Some(Held::Paused) | Some(Held::CoolingDown(_)) => limited += 1,
+                Some(Held::Suppressed(recorded)) => {
+                    if let Err(err) = recorded {
+                        error!("record flag of message {} in batch {}: {}", item.id, batch, err);
+                    }
+                    suppressed += 1;
+                }
+                None => match self.post_flag(&c.from, item, reason).await {
+                    Ok((posted, recorded)) => {
+                        if let Err(err) = recorded {
+                            error!("record flag of message {} in batch {}: {}", item.id, batch, err);
+                        }
+                        match posted {
+                            Posted::Sent => sent += 1,
+                            Posted::Suppressed => suppressed += 1,
+                        }
+                    }
+                    Err(_) => failed += 1,
+                },
+            }
+        }
+
+        let mut result = format!("发送 {} 条", sent);
+        if suppressed > 0 {
+            result.push_str(&format!("，{} 条用户提醒冷却中仅记录", suppressed));
+        }
+        if limited > 0 {
+            result.push_str(&format!("，{} 条操作过于频繁被跳过", limited));
+        }
+        if failed > 0 {
+            result.push_str(&format!("，{} 条发送失败稍后将重试", failed));
+        }
+        info!("batch {} flagged as {} by {}: {}", batch, reason.id, c.from.id, result);
+
+        let (chat, id) = b.prompt;
+        let mut msg = EditMessageText::new(
+            chat,
+            id,
+            format!(
+                "{} 条消息已由 {} 批量处理：{}\n{}",
+                b.items.len(),
+                escape_markdown(&full_name(&c.from)),
+                reason.label,
+                result
+            ),
+        );
+        msg.parse_mode(ParseMode::Markdown);
+        if let Err(err) = self.api.send(msg).await {
+            warn!("close batch prompt {} in chat {}: {}", id, chat, err);
+        }
+
+        let mut msg = SendMessage::new(
+            ChatId::from(self.cfg.admin_group),
+            format!(
+                "{} 将 {} 条消息批量标记为{}：{}",
+                self.mention(&c.from),
+                b.items.len(),
+                reason.label,
+                result
+            ),
+        );
+        msg.parse_mode(ParseMode::Markdown);
+        if !self.cfg.audit_notify {
+            msg.disable_notification();
+        }
+        if let Err(err) = self.api.send(msg).await {
+            error!("send audit for batch {}: {}", batch, err);
+        }
+
+        Ok(())
+    }
+
+    /// Notify admins if many users have posted the same text as the message.
+    pub async fn check_spam_wave(&self, m: &Message) {
+        let text = match message_text(m) {
+            Some(text) if self.cfg.normalize_text => normalize(text),
+            Some(text) => text.clone(),
+            None => return,
+        };
+        if text.chars().count() < self.cfg.spam_wave_min_len {
+            return;
+        }
+        if self.admins.borrow().contains(&m.from.id) {
+            return;
+        }
+
+        let observed = self.waves.borrow_mut().observe(&text, m.from.id, m.id, now());
+        let ids = match observed {
+            Some(ids) => ids,
+            None => {
+                // Later messages of a wave are deleted as well in attack mode.
+                let reported = self.waves.borrow().is_reported(&text, now());
+                if reported && self.attack.borrow().is_some() {
+                    self.delete_spam(m.id).await;
+                }
+                return;
+            }
+        };
+        warn!("spam wave of {} messages detected: {:?}", ids.len(), ids);
+        self.fire_hooks(config::Event::SpamWave, json!({ "messages": ids, "text": text }));
+
+        if self.record_wave().await {
+            for id in ids.iter() {
+                self.delete_spam(*id).await;
+            }
+            return;
+        }
+
+        let mut text = format!(
+            "检测到 {} 秒内有 {} 条相同内容的消息：{}",
+            self.cfg.spam_wave_window,
+            ids.len(),
+            summarize(m, self.cfg.normalize_text)
+        );
+        for id in ids.iter() {
+            text.push('\n');
+            text.push_str(&self.main_message_link(*id).await);
+        }
+
+        let mut msg = SendMessage::new(ChatId::from(self.cfg.admin_group), text);
+        msg.disable_preview();
+        if let Err(err) = self.api.send(msg).await {
+            error!("notify spam wave: {}", err);
+        }
+    }
+
+    /// Record a detected spam wave, entering attack mode if waves are
+    /// frequent enough. Returns whether attack mode is on.
+    pub async fn record_wave(&self) -> bool {
+        let cfg = match &self.cfg.attack {
+            Some(cfg) => cfg,
+            None => return false,
+        };
+        let now = now();
+
+        let entered = {
+            let mut waves = self.recent_waves.borrow_mut();
+            waves.push_back(now);
+            while waves.front().is_some_and(|t| now - t >= cfg.window as Integer) {
+                waves.pop_front();
+            }
+
+            let mut attack = self.attack.borrow_mut();
+            match attack.as_mut() {
+                Some(state) => {
+                    state.last_wave = now;
+                    state.waves += 1;
+                    return true;
+                }
+                None if waves.len() >= cfg.waves => {
+                    *attack = Some(AttackState {
+                        since: now,
+                        last_wave: now,
+                        last_report: now,
+                        waves: waves.len(),
+                        deleted: 0,
+                        suppressed: 0,
+                    });
+                    waves.len()
+                }
+                None => return false,
+            }
+        };
+
+        warn!("entered attack mode after {} spam waves", entered);
+        self.notify_attack(format!(
+            "{} 秒内检测到 {} 波刷屏，已进入攻击模式：自动删除刷屏消息，暂停主群提醒",
+            cfg.window, entered
+        ))
+        .await;
+        true
+    }
+
+    /// Post a summary of attack mode periodically, leaving it once no wave
+    /// is detected for a window.
+    pub async fn report_attack(&self) {
+        let cfg = match &self.cfg.attack {
+            Some(cfg) => cfg,
+            None => return,
+        };
+        let now = now();
+
+        let text = {
+            let mut attack = self.attack.borrow_mut();
+            let state = match attack.as_mut() {
+                Some(state) => state,
+                None => return,
+            };
+            let activity = format!(
+                "共 {} 波刷屏，删除 {} 条消息，暂停 {} 条提醒",
+                state.waves, state.deleted, state.suppressed
+            );
+            if now - state.last_wave >= cfg.window as Integer {
+                let text = format!(
+                    "刷屏已平息，退出攻击模式，持续 {} 分钟，{}",
+                    (now - state.since) / 60,
+                    activity
+                );
+                *attack = None;
+                text
+            } else if now - state.last_report >= cfg.summary_interval as Integer {
+                state.last_report = now;
+                format!("攻击模式中，{}", activity)
+            } else {
+                return;
+            }
+        };
+
+        info!("attack mode: {}", text);
+        self.notify_attack(text).await;
+    }
+
+    async fn notify_attack(&self, text: String) {
+        let msg = SendMessage::new(ChatId::from(self.cfg.admin_group), text);
+        if let Err(err) = self.api.send(msg).await {
+            error!("notify attack mode: {}", err);
+        }
+    }
+
+    /// Delete a message of spam waves in main group.
+    pub async fn delete_spam(&self, id: MessageId) {
+        let chat = ChatId::from(self.cfg.main_group);
+        match self.api.send(DeleteMessage::new(chat, id)).await {
+            Ok(_) => {
+                if let Some(state) = self.attack.borrow_mut().as_mut() {
+                    state.deleted += 1;
+                }
+            }
+            Err(err) => error!("delete spam message {}: {}", id, err),
+        }
+    }
+
+    /// Alert the message automatically if it matches a keyword filter.
+    pub async fn check_filters(&self, m: &Message) {
+        if self.cfg.filters.is_empty() || self.paused.get() {
+            return;
+        }
+        let text = match message_text(m) {
+            Some(text) if self.cfg.normalize_text => normalize(text),
+            Some(text) => text.clone(),
+            None => return,
+        };
+        let f = match self.cfg.filters.iter().find(|f| filter::matches(f, &text)) {
+            Some(f) => f,
+            None => return,
+        };
+        // Reasons of filters have been checked while starting.
+        let reason = match self.cfg.reason(&f.reason) {
+            Some(reason) => reason,
+            None => return,
+        };
+        if self.is_admin(&m.from).await {
+            return;
+        }
+        if self.user_reminders.borrow_mut().hit(m.from.id, now()).is_err() {
+            self.log_reason(
+                reason,
+                format_args!("user {} has been reminded recently", m.from.id),
+            );
+            return;
+        }
+        info!("message {} matches filter {}", m.id, f.keyword);
+
+        match self.send_ot_alert(m.id, reason).await {
+            Ok(Posted::Sent) => {}
+            Ok(Posted::Suppressed) => return,
+            Err(err) => {
+                error!("alert filtered message {}: {}", m.id, err);
+                self.bury(
+                    Action::Alert {
+                        id: m.id,
+                        reason: reason.id.clone(),
+                    },
+                    &err,
+                );
+                return;
+            }
+        }
+
+        let mut msg = SendMessage::new(
+            ChatId::from(self.cfg.admin_group),
+            format!(
+                "{} 的消息命中关键词 {}，已自动标记为{}",
+                self.mention(&m.from),
+                escape_markdown(&f.keyword),
+                reason.label
+            ),
+        );
+        msg.parse_mode(ParseMode::Markdown);
+        if !self.cfg.audit_notify {
+            msg.disable_notification();
+        }
+        if let Err(err) = self.api.send(msg).await {
+            error!("send audit for filtered message {}: {}", m.id, err);
+        }
+        self.fire_hooks(
+            config::Event::AutoAlert,
+            json!({
+                "message": m.id,
+                "reason": reason.id,
+                "author": m.from.id,
+                "keyword": f.keyword,
+            }),
+        );
+    }
+
+    /// Act on the message if it's written in a script main group doesn't
+    /// allow.
+    pub async fn check_scripts(&self, m: &Message) {
+        let cfg = match &self.cfg.scripts {
+            Some(cfg) => cfg,
+            None => return,
+        };
+        let text = match message_text(m) {
+            Some(text) if self.cfg.normalize_text => normalize(text),
+            Some(text) => text.clone(),
+            None => return,
+        };
+        let (detected, share) = match script::detect(&text, cfg.min_letters) {
+            Some(detected) => detected,
+            None => return,
+        };
+        if cfg.allowed.contains(&detected) || share < cfg.confidence {
+            return;
+        }
+        if self.is_admin(&m.from).await {
+            return;
+        }
+        info!("message {} is written in {:?} ({:.2})", m.id, detected, share);
+
+        match cfg.action {
+            config::ScriptAction::Notify => {
+                self.push_audit(format!(
+                    "{} 的消息疑似使用了不允许的文字（{:?}，{:.0}%）：{}",
+                    self.mention(&m.from),
+                    detected,
+                    share * 100.0,
+                    escape_markdown(&self.main_message_link(m.id).await)
+                ))
+                .await;
+            }
+            config::ScriptAction::Remind => {
+                if self.user_reminders.borrow_mut().hit(m.from.id, now()).is_err() {
+                    debug!("user {} has been reminded recently", m.from.id);
+                    return;
+                }
+                if let Err(err) = self.api.send(m.text_reply(cfg.text.as_str())).await {
+                    error!("remind script of message {}: {}", m.id, err);
+                }
+            }
+        }
+    }
+
+    /// Score the message and take the action of the threshold it reaches.
+    pub async fn check_spam_score(&self, m: &Message) {
+        let scorer = match &self.spam_scorer {
+            Some(scorer) => scorer,
+            None => return,
+        };
+        if matches!(m.kind, MessageKind::NewChatMembers { .. }) {
+            return;
+        }
+        if self.admins.borrow().contains(&m.from.id) {
+            return;
+        }
+
+        let text = match message_text(m) {
+            Some(text) if self.cfg.normalize_text => normalize(text),
+            Some(text) => text.clone(),
+            None => String::new(),
+        };
+        let score = scorer
+            .borrow_mut()
+            .observe(m.from.id, &text, count_links(m), now());
+        debug!("spam score of message {}: {:.2} {:?}", m.id, score.total, score.parts);
+        let action = match scorer.borrow().action(&score) {
+            Some(action) => action,
+            None => return,
+        };
+        if self.paused.get() || self.is_admin(&m.from).await {
+            return;
+        }
+        warn!(
+            "message {} of user {} scored {:.2}, take action {:?}",
+            m.id, m.from.id, score.total, action
+        );
+
+        let mut done = Vec::new();
+        let mut unrecorded = None;
+        if matches!(action, config::SpamAction::Delete | config::SpamAction::Ban) {
+            match self.api.send(DeleteMessage::new(m.chat.id(), m.id)).await {
+                Ok(_) => done.push("已删除消息"),
+                Err(err) => error!("delete message {} of spam score: {}", m.id, err),
+            }
+        }
+        if action == config::SpamAction::Ban {
+            match self.api.send(KickChatMember::new(m.chat.id(), m.from.id)).await {
+                Ok(_) => {
+                    let recorded = self.cache.borrow_mut().push_ban(m.from.id, now());
+                    if let Err(err) = recorded {
+                        error!("record ban of user {}: {}", m.from.id, err);
+                        self.page(
+                            Critical::WriteFailed,
+                            &format!("记录用户 {} 的封禁失败：{}", m.from.id, err),
+                        )
+                        .await;
+                        unrecorded = Some(err);
+                    }
+                    done.push("已封禁用户")
+                }
+                Err(err) => error!("ban user {} of spam score: {}", m.from.id, err),
+            }
+        }
+        self.fire_hooks(
+            config::Event::SpamScore,
+            json!({
+                "message": m.id,
+                "author": m.from.id,
+                "score": score.total,
+                "action": action,
+            }),
+        );
+
+        // Floods would notify on every message otherwise, bans that can't be
+        // recorded are always reported.
+        if unrecorded.is_none() && self.spam_alerts.borrow_mut().hit(m.from.id, now()).is_err() {
+            return;
+        }
+        let parts: Vec<_> = score
+            .parts
+            .iter()
+            .map(|(name, v)| format!("{} {:.1}", name, v))
+            .collect();
+        let mut text = format!(
+            "{} ({}) 的消息垃圾分数为 {:.1}（{}）：{}\n",
+            self.mention(&m.from),
+            m.from.id,
+            score.total,
+            escape_markdown(&parts.join("，")),
+            escape_markdown(&summarize(m, self.cfg.normalize_text))
+        );
+        if done.is_empty() {
+            text.push_str(&escape_markdown(&self.main_message_link(m.id).await));
+        } else {
+            text.push_str(&done.join("，"));
+        }
+        if let Some(err) = unrecorded {
+            text.push_str(&format!("\n封禁记录写入失败：{}", escape_markdown(&err.to_string())));
+        }
+
+        let mut msg = SendMessage::new(ChatId::from(self.cfg.admin_group), text);
+        msg.parse_mode(ParseMode::Markdown);
+        msg.disable_preview();
+        if !self.cfg.audit_notify {
+            msg.disable_notification();
+        }
+        if let Err(err) = self.api.send(msg).await {
+            error!("send spam score of message {}: {}", m.id, err);
+        }
+    }
+
+    // Automatic alerts are best-effort as well, failed ones are kept in dead
+    // letter queue like the ones decided by admins.
+    pub async fn check_stickers(&self, m: &Message) {
+        let reason = match self.cfg.sticker_reason.as_ref().and_then(|id| self.cfg.reason(id)) {
+            Some(reason) => reason,
+            None => return,
+        };
+        if self.paused.get() {
+            return;
+        }
+
+        let now = now();
+        if self.stickers.borrow_mut().hit(m.from.id, now).is_ok() {
+            return;
+        }
+        if self.is_admin(&m.from).await {
+            return;
+        }
+        // Remind only once per window however many stickers are posted.
+        if self.sticker_alerts.borrow_mut().hit(m.from.id, now).is_err() {
+            self.log_reason(
+                reason,
+                format_args!("user {} has been alerted of stickers recently", m.from.id),
+            );
+            return;
+        }
+
+        match self.send_ot_alert(m.id, reason).await {
+            Ok(Posted::Sent) => {}
+            Ok(Posted::Suppressed) => return,
+            Err(err) => {
+                error!("alert stickers of user {}: {}", m.from.id, err);
+                self.bury(
+                    Action::Alert {
+                        id: m.id,
+                        reason: reason.id.clone(),
+                    },
+                    &err,
+                );
+                return;
+            }
+        }
+
+        let mut msg = SendMessage::new(
+            ChatId::from(self.cfg.admin_group),
+            format!(
+                "{} 短时间内发送了过多贴纸或动图，已自动标记为{}",
+                self.mention(&m.from),
+                reason.label
+            ),
+        );
+        msg.parse_mode(ParseMode::Markdown);
+        if !self.cfg.audit_notify {
+            msg.disable_notification();
+        }
+        if let Err(err) = self.api.send(msg).await {
+            error!("send audit for stickers of user {}: {}", m.from.id, err);
+        }
+        self.fire_hooks(
+            config::Event::AutoAlert,
+            json!({ "message": m.id, "reason": reason.id, "author": m.from.id }),
+        );
+    }
+
+    /// Fire hooks attached to the event with details of the action.
+    pub fn fire_hooks(&self, event: config::Event, mut details: serde_json::Value) {
+        if self.cfg.hooks.is_empty() {
+            return;
+        }
+        if let Some(obj) = details.as_object_mut() {
+            obj.insert("event".to_string(), json!(event));
+            obj.insert("instance".to_string(), json!(self.instance));
+            obj.insert("time".to_string(), json!(now()));
+        }
+        hook::fire(&self.cfg.hooks, event, &details);
+    }
+
+    /// Build the keyboard of reasons that apply to the forwarded message,
+    /// all reasons are offered if the message is unknown.
+    pub fn reasons_keyboard(
+        &self,
+        m: Option<&Message>,
+        oid: MessageId,
+    ) -> Result<InlineKeyboardMarkup> {
+        let kind = m.map(content_kind);
+        let lang = m.and_then(|m| m.from.language_code.as_deref());
+        let reasons: Vec<_> = self
+            .cfg
+            .reasons
+            .iter()
+            .filter(|r| match kind {
+                Some(kind) => r.kinds.is_empty() || r.kinds.contains(&kind),
+                None => true,
+            })
+            .collect();
+
+        let mut ikm = InlineKeyboardMarkup::new();
+        for reasons in reasons.chunks(3) {
+            let mut row = Vec::with_capacity(reasons.len());
+            for reason in reasons {
+                let data = Callback::Reason {
+                    id: oid,
+                    reason: reason.id.clone(),
+                }
+                .encode(self.cfg.callback_format)?;
+                row.push(InlineKeyboardButton::callback(self.button_label(reason, lang), data));
+            }
+            ikm.add_row(row);
+        }
+
+        Ok(ikm)
+    }
+
+    /// Label of the reason's button, combined labels too long for a button
+    /// fall back to `label`.
+    fn button_label(&self, reason: &config::Reason, lang: Option<&str>) -> String {
+        let label = self.cfg.button_label(reason, lang);
+        if label.chars().count() > MAX_BUTTON_LABEL_LEN {
+            return reason.label.clone();
+        }
+        label
+    }
+
+    /// Mark all open prompts of the original message as handled, so that
+    /// other admins won't act on the same message again.
+    pub async fn close_prompts(&self, original_message_id: MessageId, admin: &User, reason: &str) {
+        let prompts = match self.cache.borrow_mut().take_prompts(original_message_id) {
+            Ok(prompts) => prompts,
+            Err(err) => {
+                warn!("take prompts of message {}: {}", original_message_id, err);
+                return;
+            }
+        };
+
+        for (chat, id) in prompts {
+            let mut msg = EditMessageText::new(
+                chat,
+                id,
+                format!("该消息已由 {} 处理：{}", escape_markdown(&full_name(admin)), reason),
+            );
+            msg.parse_mode(ParseMode::Markdown);
+
+            if let Err(err) = self.api.send(msg).await {
+                warn!("close prompt {} in chat {}: {}", id, chat, err);
+            }
+        }
+    }
+
+    /// Post the alert of the reason for the message, tracked so that it can
+    /// be buried if shutdown cuts it off.
+    pub async fn send_ot_alert(
+        &self,
+        original_message_id: MessageId,
+        reason: &config::Reason,
+    ) -> Result<Posted> {
+        *self.alerting.borrow_mut() = Some(Action::Alert {
+            id: original_message_id,
+            reason: reason.id.clone(),
+        });
+        let result = self.post_alert(original_message_id, reason).await;
+        *self.alerting.borrow_mut() = None;
+        result
+    }
+
+    async fn post_alert(
+        &self,
+        original_message_id: MessageId,
+        reason: &config::Reason,
+    ) -> Result<Posted> {
+        let chat = match self.alert_chat(reason) {
+            Some(chat) => chat,
+            None => {
+                self.log_reason(reason, format_args!("reason has no target, skip alert"));
+                return Ok(Posted::Sent);
+            }
+        };
+        // Main group is flooded already during attacks.
+        if reason.target == config::Target::Main {
+            if let Some(state) = self.attack.borrow_mut().as_mut() {
+                self.log_reason(
+                    reason,
+                    format_args!("in attack mode, skip alert of message {}", original_message_id),
+                );
+                state.suppressed += 1;
+                return Ok(Posted::Suppressed);
+            }
+        }
+
+        self.log_reason(
+            reason,
+            format_args!("post alert of message {} to chat {}", original_message_id, chat),
+        );
+        // Alerts outside main group can't reply to the original message.
+        if reason.target != config::Target::Main {
+            let msg = SendMessage::new(
+                ChatId::from(chat),
+                format!(
+                    "{}\n{}",
+                    reason.text,
+                    self.main_message_link(original_message_id).await
+                ),
+            );
+
+            self.api.send(msg).await?;
+            self.stats.borrow_mut().alerts_sent += 1;
+
+            return Ok(Posted::Sent);
+        }
+
+        let mut msg = SendMessage::new(ChatId::from(chat), reason.text.as_str());
+        if let Some(ikm) = self.alert_keyboard(reason, original_message_id).await {
+            msg.reply_markup(ikm);
+        }
+        msg.reply_to(original_message_id);
+        msg.parse_mode(ParseMode::Markdown);
+
+        let sent = self.api.send(msg).await?;
+        self.stats.borrow_mut().alerts_sent += 1;
+
+        // Appeals of the message are shown under the alert.
+        if let (Some(_), MessageOrChannelPost::Message(sent)) = (self.cfg.appeal_queue, sent) {
+            let alert = cache::Alert {
+                chat: sent.chat.id(),
+                id: sent.id,
+                reason: reason.id.clone(),
+            };
+            // The alert is out already, failing here would post it again.
+            let stored = self.cache.borrow_mut().set_alert(original_message_id, &alert);
+            if let Err(err) = stored {
+                error!("record alert of message {}: {}", original_message_id, err);
+            }
+        }
+
+        Ok(Posted::Sent)
+    }
+
+    /// Build buttons under the alert of the message.
+    async fn alert_keyboard(
+        &self,
+        reason: &config::Reason,
+        original_message_id: MessageId,
+    ) -> Option<InlineKeyboardMarkup> {
+        // Add button for ot group
+        let mut links = vec![(self.cfg.buttons.offtopic_label(), self.cfg.offtopic_group.clone())];
+        let appeal = match reason.appeal {
+            config::Appeal::Meta if self.cfg.appeal_queue.is_none() => {
+                Some(self.cfg.meta_group.clone())
+            }
+            config::Appeal::Meta | config::Appeal::Dm => self.me.username.as_ref().map(|me| {
+                // Appeals in the queue are linked to the alert.
+                match self.cfg.appeal_queue {
+                    Some(_) => format!(
+                        "https://t.me/{}?start={}_{}",
+                        me, APPEAL_START, original_message_id
+                    ),
+                    None => format!("https://t.me/{}?start={}", me, APPEAL_START),
+                }
+            }),
+            config::Appeal::Url => reason.appeal_url.clone(),
+            config::Appeal::None => None,
+        };
+        if let Some(url) = appeal {
+            links.push((self.cfg.buttons.appeal_label(), url));
+        }
+        if let Some(label) = self.cfg.buttons.rules_label() {
+            let rules = self.cache.borrow().rules();
+            match rules {
+                Ok(Some(rules)) => links.push((label, self.main_message_link(rules.id).await)),
+                Ok(None) => {}
+                Err(err) => error!("get rules: {}", err),
+            }
+        }
+        // Telegram rejects the whole alert for a single broken link.
+        let mut row = Vec::with_capacity(links.len());
+        for (label, url) in links {
+            if self.check_button_url(&url).await {
+                row.push(InlineKeyboardButton::url(label, url));
+            }
+        }
+        if row.is_empty() {
+            return None;
+        }
+        let mut ikm = InlineKeyboardMarkup::new();
+        ikm.add_row(row);
+        Some(ikm)
+    }
+
+    /// Text and buttons of the appeal record in `appeal_queue`, resolved
+    /// appeals have no buttons.
+    fn appeal_record(
+        &self,
+        id: u64,
+        a: &cache::Appeal,
+    ) -> Result<(String, Option<InlineKeyboardMarkup>)> {
+        let mut text = format!(
+            "申诉 #{}：{}（{}）\n状态：{}\n\n{}",
+            id,
+            a.user_name,
+            a.user,
+            appeal_label(a.status),
+            escape_markdown(&a.text)
+        );
+        if let Some(original) = a.message {
+            text.push_str(&format!("\n\n被提醒的消息：{}", original));
+        }
+
+        let next: &[cache::AppealStatus] = match a.status {
+            cache::AppealStatus::Open => {
+                &[cache::AppealStatus::Reviewing, cache::AppealStatus::Resolved]
+            }
+            cache::AppealStatus::Reviewing => &[cache::AppealStatus::Resolved],
+            cache::AppealStatus::Resolved => &[],
+        };
+        if next.is_empty() {
+            return Ok((text, None));
+        }
+        let mut row = Vec::with_capacity(next.len());
+        for status in next {
+            let data = Callback::Appeal {
+                id,
+                status: *status,
+            }
+            .encode(self.cfg.callback_format)?;
+            row.push(InlineKeyboardButton::callback(
+                format!("标记为{}", appeal_label(*status)),
+                data,
+            ));
+        }
+        let mut ikm = InlineKeyboardMarkup::new();
+        ikm.add_row(row);
+        Ok((text, Some(ikm)))
+    }
+
+    /// Show status of the appeal under the alert it appeals, errors are
+    /// only logged since the alert may be deleted already.
+    async fn show_appeal(&self, a: &cache::Appeal) {
+        let original = match a.message {
+            Some(original) => original,
+            None => return,
+        };
+        let alert = match self.cache.borrow().alert(original) {
+            Ok(Some(alert)) => alert,
+            Ok(None) => return,
+            Err(err) => {
+                error!("get alert of message {}: {}", original, err);
+                return;
+            }
+        };
+        let reason = match self.cfg.reason(&alert.reason) {
+            Some(reason) => reason,
+            None => return,
+        };
+
+        let mut msg = EditMessageText::new(
+            alert.chat,
+            alert.id,
+            format!("{}\n\n申诉{}", reason.text, appeal_label(a.status)),
+        );
+        msg.parse_mode(ParseMode::Markdown);
+        if let Some(ikm) = self.alert_keyboard(reason, original).await {
+            msg.reply_markup(ikm);
+        }
+        if let Err(err) = self.api.send(msg).await {
+            warn!("show appeal under alert {}: {}", alert.id, err);
+        }
+    }
+
+    /// Check whether telegram takes the url for a button, invalid urls are
+    /// reported to admin group once.
+    async fn check_button_url(&self, url: &str) -> bool {
+        if is_button_url(url) {
+            return true;
+        }
+        warn!("button url {:?} is invalid, omit the button", url);
+        if !self.invalid_urls.borrow_mut().insert(url.to_string()) {
+            return false;
+        }
+        let mut msg = SendMessage::new(
+            ChatId::from(self.cfg.admin_group),
+            format!("按钮链接 {} 无效，提醒中已省略该按钮，请检查配置", url),
+        );
+        if !self.cfg.audit_notify {
+            msg.disable_notification();
+        }
+        if let Err(err) = self.api.send(msg).await {
+            error!("report invalid url {:?}: {}", url, err);
+        }
+        false
+    }
+
+    /// Returns the chat that alerts of the reason are posted to.
+    pub fn alert_chat(&self, reason: &config::Reason) -> Option<i64> {
+        match reason.target {
+            config::Target::Main => Some(self.cfg.main_group),
+            config::Target::Admin => Some(self.cfg.admin_group),
+            // Log channel has been checked while starting.
+            config::Target::Log => self.cfg.log_channel,
+            config::Target::None => None,
+        }
+    }
+
+    // Audit messages are best-effort, failing to send them should not affect
+    // the alert that has already been posted.
+    pub async fn send_audit(&self, c: &CallbackQuery, reason: &config::Reason, label: &str) {
+        let author = match forwarded_message(c).and_then(|m| m.forward.as_ref()) {
+            Some(Forward {
+                from: ForwardFrom::User { user },
+                ..
+            }) if is_deleted_account(user) => format!("已注销账号 ({})", user.id),
+            Some(Forward {
+                from: ForwardFrom::User { user },
+                ..
+            }) => self.mention(user),
+            Some(Forward {
+                from: ForwardFrom::ChannelHiddenUser { sender_name },
+                ..
+            }) => escape_markdown(sender_name),
+            _ => "未知用户".to_string(),
+        };
+
+        let mut text = format!("{} 将 {} 的消息标记为{}", self.mention(&c.from), author, label);
+        // Admin group can't see the forward, quote what was said in the group
+        // or link to it for reasons not quoted.
+        match forwarded_message(c).and_then(|m| self.get_original_message(m)) {
+            Some(original) if !self.cfg.audit_quote(reason) => {
+                text.push_str("\n原消息：");
+                text.push_str(&escape_markdown(&self.main_message_link(original.id).await));
+            }
+            Some(cache::CachedMessage {
+                text: Some(original),
+                ..
+            }) => {
+                let original = if self.cfg.normalize_text {
+                    normalize(&original)
+                } else {
+                    original
+                };
+                text.push_str("\n原文：");
+                text.push_str(&escape_markdown(&original));
+            }
+            _ => {}
+        }
+
+        self.push_audit(text).await;
+    }
+
+    /// Send the audit, or collect it into the digest if `audit_window` is set.
+    pub async fn push_audit(&self, text: String) {
+        if self.cfg.audit_window == 0 {
+            self.send_audit_text(text).await;
+            return;
+        }
+        let full = {
+            let mut audits = self.audits.borrow_mut();
+            if audits.1.is_empty() {
+                audits.0 = now();
+            }
+            audits.1.push(text);
+            audits.1.len() >= self.cfg.audit_max
+                || audits.1.iter().map(|t| t.chars().count()).sum::<usize>() >= AUDIT_DIGEST_LEN
+        };
+        if full {
+            self.flush_audits().await;
+        }
+    }
+
+    /// Send the digest once `audit_window` has passed.
+    pub async fn flush_due_audits(&self) {
+        let due = {
+            let audits = self.audits.borrow();
+            !audits.1.is_empty() && now() - audits.0 >= self.cfg.audit_window as Integer
+        };
+        if due {
+            self.flush_audits().await;
+        }
+    }
+
+    /// Send all collected audits in one message.
+    pub async fn flush_audits(&self) {
+        let audits = std::mem::take(&mut self.audits.borrow_mut().1);
+        let text = match audits.len() {
+            0 => return,
+            1 => audits.join(""),
+            n => format!("{} 条审计记录：\n\n{}", n, audits.join("\n\n")),
+        };
+        self.send_audit_text(text).await;
+    }
+
+    async fn send_audit_text(&self, text: String) {
+        let mut msg = SendMessage::new(ChatId::from(self.cfg.admin_group), text);
+        msg.parse_mode(ParseMode::Markdown);
+        if !self.cfg.audit_notify {
+            msg.disable_notification();
+        }
+
+        if let Err(err) = self.api.send(msg).await {
+            error!("send audit: {}", err);
+            self.page(Critical::WriteFailed, &format!("审计记录发送失败：{}", err))
+                .await;
+        }
+    }
+
+    /// Format user as a markdown mention according to `mention_style`.
+    pub fn mention(&self, user: &User) -> String {
+        let name = full_name(user);
+
+        match (self.cfg.mention_style, &user.username) {
+            (config::MentionStyle::Mention, Some(username)) => {
+                format!("@{}", escape_markdown(username))
+            }
+            (config::MentionStyle::Mention, None) | (config::MentionStyle::Link, _) => {
+                format!("[{}](tg://user?id={})", escape_markdown(&name), user.id)
+            }
+            (config::MentionStyle::Plain, _) => escape_markdown(&name),
+        }
+    }
+
+    pub fn menu_keyboard(&self) -> Result<InlineKeyboardMarkup> {
+        let pause = if self.paused.get() { "恢复提醒" } else { "暂停提醒" };
+
+        let mut ikm = InlineKeyboardMarkup::new();
+        ikm.add_row(vec![
+            InlineKeyboardButton::callback(
+                pause,
+                Callback::Menu { action: MenuAction::Pause }.encode(self.cfg.callback_format)?,
+            ),
+            InlineKeyboardButton::callback(
+                "统计",
+                Callback::Menu { action: MenuAction::Stats }.encode(self.cfg.callback_format)?,
+            ),
+            InlineKeyboardButton::callback(
+                "刷新管理员",
+                Callback::Menu {
+                    action: MenuAction::ReloadAdmins,
+                }
+                .encode(self.cfg.callback_format)?,
+            ),
+        ]);
+
+        Ok(ikm)
+    }
+
+    /// Replace known admins with the current admins of main group, returns
+    /// how many admins there are.
+    pub async fn reload_admins(&self) -> Result<usize> {
+        let admins = fetch_admins(&self.api, &self.cfg).await?;
+
+        warn_unusable_admins(&self.cfg, &admins);
+
+        let mut h = self.admins.borrow_mut();
+        h.clear();
+        h.extend(admins.iter().map(|m| m.user.id));
+        override_admins(&self.cfg, &mut h);
+
+        Ok(h.len())
+    }
+
+    pub async fn send_admins<M: CanReplySendMessage>(&self, m: &M) -> Result<()> {
+        let (text, ikm) = self.admins_page(self.cfg.admin_groups()[0], 0).await?;
+
+        let mut msg = m.text_reply(text);
+        msg.parse_mode(ParseMode::Markdown);
+        msg.reply_markup(ikm);
+
+        self.api.send(msg).await?;
+
+        Ok(())
+    }
+
+    /// Render one page of the group's admins with buttons to turn pages.
+    ///
+    /// Admins are fetched on every call so that pages always reflect the
+    /// current admin list.
+    pub async fn admins_page(
+        &self,
+        group: i64,
+        offset: usize,
+    ) -> Result<(String, InlineKeyboardMarkup)> {
+        let mut admins = self
+            .api
+            .send(GetChatAdministrators::new(ChatId::from(group)))
+            .await?;
+        admins.sort_by_cached_key(|m| full_name(&m.user).to_lowercase());
+
+        let total = admins.len();
+        let offset = offset.min(total.saturating_sub(1)) / ADMINS_PAGE_SIZE * ADMINS_PAGE_SIZE;
+        let end = (offset + ADMINS_PAGE_SIZE).min(total);
+
+        let mut text = format!("管理员 {}-{}，共 {} 人：", offset + 1, end, total);
+        for (i, m) in admins[offset..end].iter().enumerate() {
+            text.push_str(&format!(
+                "\n{}. {} ({})",
+                offset + i + 1,
+                escape_markdown(&full_name(&m.user)),
+                m.user.id
+            ));
+        }
+
+        let mut row = Vec::new();
+        if offset > 0 {
+            let data = Callback::AdminsPage {
+                group,
+                offset: offset - ADMINS_PAGE_SIZE,
+            }
+            .encode(self.cfg.callback_format)?;
+            row.push(InlineKeyboardButton::callback("上一页", data));
+        }
+        if end < total {
+            let data = Callback::AdminsPage { group, offset: end }.encode(self.cfg.callback_format)?;
+            row.push(InlineKeyboardButton::callback("下一页", data));
+        }
+
+        let mut ikm = InlineKeyboardMarkup::new();
+        if !row.is_empty() {
+            ikm.add_row(row);
+        }
+
+        Ok((text, ikm))
+    }
+
+    /// Page the oncall chat with a critical error, at most once per category
+    /// in `oncall_interval`.
+    pub async fn page(&self, category: Critical, text: &str) {
+        let chat = match self.cfg.oncall_chat {
+            Some(chat) => chat,
+            None => return,
+        };
+        if self.oncall.borrow_mut().hit(category, now()).is_err() {
+            debug!("{:?} has been paged recently, skip", category);
+            return;
+        }
+
+        let msg = SendMessage::new(ChatId::from(chat), format!("【{}】{}：{}", category, self.instance, text));
+        if let Err(err) = self.api.send(msg).await {
+            error!("page {:?} to oncall chat {}: {}", category, chat, err);
+        }
+    }
+
+    /// Give up the update cut off by shutdown, burying the alert it was
+    /// posting so that it's retried after restart.
+    fn interrupt(&self, u: &Update) {
+        warn!("update {} is interrupted by shutdown", u.id);
+        if let Some(action) = self.alerting.borrow_mut().take() {
+            self.bury(action, &anyhow!("interrupted by shutdown"));
+        }
+    }
+
+    /// Keep a failed action in dead letter queue for later retries.
+    pub fn bury(&self, action: Action, err: &anyhow::Error) {
+        let d = DeadLetter {
+            action,
+            time: now(),
+            attempts: 1,
+            error: err.to_string(),
+        };
+        if let Err(err) = self
+            .cache
+            .borrow_mut()
+            .push_dead_letter(&d, self.cfg.deadletter_max)
+        {
+            error!("push dead letter {:?}: {}", d, err);
+        }
+    }
+
+    pub async fn execute(&self, action: &Action) -> Result<()> {
+        match action {
+            Action::Alert { id, reason } => {
+                let reason = self
+                    .cfg
+                    .reason(reason)
+                    .ok_or_else(|| anyhow!("reason {} not found", reason))?;
+                self.send_ot_alert(*id, reason).await.map(|_| ())
+            }
+        }
+    }
+
+    /// Retry all actions in dead letter queue, returns how many actions have
+    /// been retried and how many of them succeeded.
+    pub async fn retry_dead_letters(&self) -> Result<(usize, usize)> {
+        let letters = self.cache.borrow().dead_letters()?;
+        let expired = now() - self.cfg.deadletter_max_age as Integer;
+
+        let (mut total, mut done) = (0, 0);
+        for (key, d) in letters {
+            if d.time < expired {
+                warn!("dead letter {:?} expired, give up", d);
+                self.cache.borrow_mut().remove_dead_letter(key)?;
+                self.page(
+                    Critical::ActionDropped,
+                    &format!("操作 {:?} 重试 {} 次后放弃：{}", d.action, d.attempts, d.error),
+                )
+                .await;
+                continue;
+            }
+
+            total += 1;
+            match self.execute(&d.action).await {
+                Ok(_) => {
+                    done += 1;
+                    self.cache.borrow_mut().remove_dead_letter(key)?;
+                }
+                Err(err) => {
+                    self.cache
+                        .borrow_mut()
+                        .fail_dead_letter(key, &err.to_string())?;
+                }
+            }
+        }
+
+        Ok((total, done))
+    }
+
+    /// Reverse everything that has piled up on the user, returns a report of
+    /// what has been reversed.
+    pub async fn forgive(&self, user: UserId) -> Result<String> {
+        let mut done = Vec::new();
+
+        let flags = self.cache.borrow_mut().resolve_author_flags(user, now())?;
+        if flags > 0 {
+            done.push(format!("处理了 {} 条未处理的标记", flags));
+        }
+        if self.user_reminders.borrow_mut().reset(&user) {
+            done.push("清除了提醒冷却".to_string());
+        }
+        if self.stickers.borrow_mut().reset(&user) | self.sticker_alerts.borrow_mut().reset(&user) {
+            done.push("清除了贴纸计数".to_string());
+        }
+        if self.cache.borrow_mut().remove_ban(user)? {
+            match self
+                .api
+                .send(UnbanChatMember::new(ChatId::from(self.cfg.main_group), user))
+                .await
+            {
+                Ok(_) => done.push("解除了封禁".to_string()),
+                Err(err) => warn!("unban user {}: {}", user, err),
+            }
+        }
+        let mute = self.cache.borrow_mut().remove_mute(user)?;
+        let chat = ChatId::from(self.cfg.main_group);
+        let restrict = match &mute {
+            Some(mute) => Some(self.restore_mute(user, mute)),
+            // Restrictions by others are lifted as well.
+            None => match self.api.send(GetChatMember::new(chat, user)).await {
+                Ok(member) if is_restricted(&member) => {
+                    Some(RestrictChatMember::new(chat, user, ChatPermissions::all()))
+                }
+                Ok(_) => None,
+                Err(err) => {
+                    warn!("get restrictions of user {}: {}", user, err);
+                    None
+                }
+            },
+        };
+        if let Some(restrict) = restrict {
+            match self.api.send(restrict).await {
+                Ok(_) => done.push("解除了禁言".to_string()),
+                Err(err) => warn!("lift restrictions of user {}: {}", user, err),
+            }
+        }
+
+        info!("user {} is forgiven: {:?}", user, done);
+        Ok(if done.is_empty() {
+            format!("用户 {} 没有需要撤销的处罚", user)
+        } else {
+            format!("已宽恕用户 {}：{}", user, done.join("，"))
+        })
+    }
+
+    /// Cache the pinned message of main group as its rules, returns a
+    /// report for the admin. Rules synced before are kept if nothing is
+    /// pinned.
+    pub async fn sync_rules(&self) -> Result<String> {
+        let chat = self
+            .api
+            .send(GetPinnedMessage::new(ChatId::from(self.cfg.main_group)))
+            .await?;
+        let pinned = match chat.pinned_message {
+            Some(pinned) => pinned,
+            None => return Ok("大群没有置顶消息，群规未更新".to_string()),
+        };
+        let text = match pinned.text.or(pinned.caption) {
+            Some(text) if !text.trim().is_empty() => text,
+            _ => return Ok("置顶消息没有文字，群规未更新".to_string()),
+        };
+
+        let rules = cache::Rules {
+            id: pinned.message_id,
+            text,
+            time: now(),
+        };
+        self.cache.borrow_mut().set_rules(&rules)?;
+        info!("rules are synced from message {}", rules.id);
+        Ok(format!("已同步群规，共 {} 字", rules.text.chars().count()))
+    }
+
+    /// Mute the user in main group for the seconds, permissions before the
+    /// mute are restored once it's lifted. Returns a report for the admin.
+    pub async fn mute(&self, user: UserId, secs: Integer) -> Result<String> {
+        let chat = ChatId::from(self.cfg.main_group);
+        let until = now() + secs;
+        // Muting again only extends the mute, the saved permissions are
+        // the ones before the first mute.
+        let saved = self.cache.borrow().mute(user)?;
+        let mute = match saved {
+            Some(saved) => cache::Mute { until, ..saved },
+            None => {
+                let (permissions, permissions_until) =
+                    match self.api.send(GetChatMember::new(chat, user)).await {
+                        Ok(member) => match &member.status {
+                            ChatMemberStatus::Creator | ChatMemberStatus::Administrator => {
+                                return Ok(format!("用户 {} 是管理员，无法禁言", user))
+                            }
+                            _ if is_restricted(&member) => {
+                                // 0 means forever.
+                                let until = member.until_date.filter(|t| *t > 0);
+                                (ChatPermissions::of_restricted(&member), until)
+                            }
+                            _ => (ChatPermissions::all(), None),
+                        },
+                        Err(err) => {
+                            warn!(
+                                "get permissions of user {}, restore group defaults after mute: {}",
+                                user, err
+                            );
+                            (ChatPermissions::all(), None)
+                        }
+                    };
+                cache::Mute {
+                    until,
+                    permissions,
+                    permissions_until,
+                }
+            }
+        };
+
+        // Telegram lifts the mute by itself in case the bot is down then,
+        // the bot restores the permissions once it's back.
+        let mut restrict = RestrictChatMember::new(chat, user, ChatPermissions::none());
+        restrict.until_date(until);
+        self.api.send(restrict).await?;
+        self.cache.borrow_mut().set_mute(user, &mute)?;
+
+        info!("user {} is muted until {}", user, until);
+        Ok(format!("已禁言用户 {}，{} 秒后解除", user, secs))
+    }
+
+    /// Restore permissions of users whose mute has expired.
+    pub async fn lift_due_mutes(&self) {
+        let mutes = match self.cache.borrow().due_mutes(now()) {
+            Ok(mutes) => mutes,
+            Err(err) => {
+                error!("get due mutes: {}", err);
+                return;
+            }
+        };
+        for (user, mute) in mutes {
+            // Not retried, a failing restore would be tried every second.
+            if let Err(err) = self.cache.borrow_mut().remove_mute(user) {
+                error!("remove mute of user {}: {}", user, err);
+                continue;
+            }
+            match self.api.send(self.restore_mute(user, &mute)).await {
+                Ok(_) => info!("mute of user {} is lifted", user),
+                Err(err) => error!("restore permissions of user {}: {}", user, err),
+            }
+        }
+    }
+
+    /// Request restoring permissions saved by the mute, restrictions that
+    /// would have been lifted during the mute are not restored.
+    fn restore_mute(&self, user: UserId, mute: &cache::Mute) -> RestrictChatMember {
+        let chat = ChatId::from(self.cfg.main_group);
+        match mute.permissions_until {
+            Some(until) if until <= now() => {
+                RestrictChatMember::new(chat, user, ChatPermissions::all())
+            }
+            Some(until) => {
+                let mut restrict = RestrictChatMember::new(chat, user, mute.permissions);
+                restrict.until_date(until);
+                restrict
+            }
+            None => RestrictChatMember::new(chat, user, mute.permissions),
+        }
+    }
+
+    /// Describe the standing of the user from their open flags, only
+    /// reasons counting toward escalation add to it.
+    pub fn whois(&self, user: UserId) -> Result<String> {
+        let flags = self.cache.borrow().author_flags(user)?;
+        let name = match flags.last() {
+            Some((_, f)) => f.author_name.clone(),
+            None => return Ok(format!("用户 {} 没有被标记过", user)),
+        };
+
+        // Reason id to open flags and whether it counts, in order of first
+        // flagged.
+        let mut reasons: Vec<(&str, usize, bool)> = Vec::new();
+        let mut standing = 0;
+        for (_, f) in flags.iter().filter(|(_, f)| f.resolved.is_none()) {
+            // Reasons removed from config count by the default.
+            let counts = self
+                .cfg
+                .reason(&f.reason)
+                .map_or(self.cfg.counts_toward_escalation, |r| {
+                    self.cfg.counts_toward_escalation(r)
+                });
+            if counts {
+                standing += 1;
+            }
+            match reasons.iter_mut().find(|(id, _, _)| *id == f.reason) {
+                Some((_, n, _)) => *n += 1,
+                None => reasons.push((&f.reason, 1, counts)),
+            }
+        }
+
+        let mut text = format!(
+            "用户 {}（{}）共被标记 {} 次，未处理 {} 次，其中 {} 次计入处罚",
+            name,
+            user,
+            flags.len(),
+            reasons.iter().map(|(_, n, _)| n).sum::<usize>(),
+            standing
         );
+        for (id, n, counts) in reasons {
+            let label = self.cfg.reason(id).map_or(id, |r| r.label.as_str());
+            let counts = if counts { "计入" } else { "不计入" };
+            text.push_str(&format!("\n{}：{} 次（{}）", label, n, counts));
+        }
+        Ok(text)
+    }
 
-        let mut ikm = InlineKeyboardMarkup::new();
-        // Add button for ot group
-        ikm.add_row(vec![
-            InlineKeyboardButton::url("跳转到 OT 群", &self.cfg.offtopic_group),
-            InlineKeyboardButton::url("申诉", &self.cfg.meta_group),
-        ]);
+    pub async fn send_open_flags<M: CanReplySendMessage>(&self, m: &M) -> Result<()> {
+        let flags = self.cache.borrow().open_flags()?;
 
-        msg.reply_markup(ikm);
-        msg.reply_to(original_message_id);
-        msg.parse_mode(ParseMode::Markdown);
+        let mut text = format!("未处理的标记共 {} 条", flags.len());
+        // Only list the oldest ones to keep the reply within message limit.
+        for (id, f) in flags.iter().take(OPEN_FLAGS_LIST_SIZE) {
+            let reason = self
+                .cfg
+                .reason(&f.reason)
+                .map_or(f.reason.as_str(), |r| r.label.as_str());
+            text.push_str(&format!(
+                "\n{}. {} 于 {} 被标记为{}",
+                id,
+                f.author_name,
+                format_time(f.time),
+                reason
+            ));
+            if let Some(message) = f.message {
+                text.push(' ');
+                text.push_str(&self.main_message_link(message).await);
+            }
+        }
+        text.push_str("\n使用 /resolve <编号> 标记为已处理");
 
+        let mut msg = m.text_reply(text);
+        msg.disable_preview();
         self.api.send(msg).await?;
 
         Ok(())
     }
+
+    pub async fn send_dead_letters<M: CanReplySendMessage>(&self, m: &M) -> Result<()> {
+        let letters = self.cache.borrow().dead_letters()?;
+
+        let mut text = format!("死信队列共 {} 条", letters.len());
+        // Only list the oldest ones to keep the reply within message limit.
+        for (key, d) in letters.iter().take(DEADLETTER_LIST_SIZE) {
+            let action = match &d.action {
+                Action::Alert { id, reason } => format!("提醒 {} ({})", id, reason),
+            };
+            text.push_str(&format!(
+                "\n{}. {} 于 {}，已尝试 {} 次：{}",
+                key,
+                action,
+                format_time(d.time),
+                d.attempts,
+                d.error
+            ));
+        }
+
+        self.api.send(m.text_reply(text)).await?;
+
+        Ok(())
+    }
+
+    pub async fn send_stats<M: CanReplySendMessage>(&self, m: &M, args: &[Value]) -> Result<()> {
+        let hours = match args.first() {
+            Some(Value::Count(v)) => (*v as Integer).max(1),
+            _ => 24,
+        };
+
+        let now = now();
+        let mut text = match self.cache.borrow().snapshot_at(now - hours * 3600)? {
+            None => "暂无统计数据".to_string(),
+            Some((t, past)) => {
+                let c = self.stats.borrow().since(&past);
+                format!(
+                    "最近 {} 小时统计：\n缓存消息：{}\n询问管理员：{}\n处理回调：{}\n离题提醒：{}",
+                    (now - t) / 3600,
+                    c.messages_cached,
+                    c.prompts_sent,
+                    c.callbacks_handled,
+                    c.alerts_sent
+                )
+            }
+        };
+        let (calls, delayed, utilization) = self.api.usage();
+        text.push_str(&format!(
+            "\n本次启动后 API 调用：{}，排队：{}，当前预算占用：{:.0}%",
+            calls,
+            delayed,
+            utilization * 100.0
+        ));
+
+        self.api.send(m.text_reply(text)).await?;
+
+        Ok(())
+    }
+
+    /// Record the daily summary scheduled at `scheduled` as done, it's
+    /// remembered in memory as well so that a failed write doesn't post it
+    /// again on every tick.
+    fn summary_done(&self, scheduled: Integer) {
+        self.last_summary.set(Some(scheduled));
+        if let Err(err) = self.cache.borrow_mut().set_last_summary(scheduled) {
+            error!("record daily summary of {}: {}", scheduled, err);
+        }
+    }
+
+    /// Post the summary of flags in the 24 hours before `summary_time` once
+    /// it's due today, summaries missed while not running are posted late.
+    pub async fn send_summary_if_due(&self) -> Result<()> {
+        let at = match &self.cfg.summary_time {
+            Some(at) => {
+                parse_time_of_day(at).ok_or_else(|| anyhow!("invalid summary_time {}", at))?
+            }
+            None => return Ok(()),
+        };
+        let chat = match self.cfg.summary_target {
+            config::SummaryTarget::Admin => self.cfg.admin_group,
+            // Log channel has been checked while starting.
+            config::SummaryTarget::Log => self
+                .cfg
+                .log_channel
+                .ok_or_else(|| anyhow!("log_channel is not set"))?,
+        };
+        let offset = self.cfg.utc_offset(chat);
+        let tz = timezone(offset).ok_or_else(|| anyhow!("invalid utc_offset {}", offset))?;
+
+        let now = now();
+        let today = tz.timestamp(now, 0).date().naive_local();
+        let scheduled = tz
+            .from_local_datetime(&today.and_time(at))
+            .single()
+            .ok_or_else(|| anyhow!("invalid summary time {} at {}", at, today))?
+            .timestamp();
+        if now < scheduled {
+            return Ok(());
+        }
+        let last = self.cache.borrow().last_summary()?.max(self.last_summary.get());
+        if last.is_some_and(|last| last >= scheduled) {
+            return Ok(());
+        }
+
+        let flags = self
+            .cache
+            .borrow()
+            .flags_between(scheduled - 86400, scheduled)?;
+        let mut summary = stats::Summary::from_flags(&flags, SUMMARY_TOP_AUTHORS);
+        {
+            let cache = self.cache.borrow();
+            summary.bans = cache.bans_between(scheduled - 86400, scheduled)?;
+            summary.appeals = cache.appeals_between(scheduled - 86400, scheduled)?;
+        }
+        if summary.flags == 0
+            && summary.bans == 0
+            && summary.appeals == 0
+            && self.cfg.summary_skip_empty
+        {
+            debug!("nothing happened since {}, skip daily summary", scheduled - 86400);
+            self.summary_done(scheduled);
+            return Ok(());
+        }
+
+        let date = tz.timestamp(scheduled - 1, 0).format("%Y-%m-%d");
+        let mut text = format!(
+            "{} {} 管理日报：\n共标记 {} 条消息",
+            escape_markdown(&self.instance),
+            date,
+            summary.flags
+        );
+        let suppressed = flags.iter().filter(|f| f.suppressed).count();
+        if suppressed > 0 {
+            text.push_str(&format!("，其中 {} 条因用户提醒冷却未提醒", suppressed));
+        }
+        if summary.bans > 0 {
+            text.push_str(&format!("\n封禁 {} 位用户", summary.bans));
+        }
+        if summary.appeals > 0 {
+            text.push_str(&format!("\n收到 {} 条申诉", summary.appeals));
+        }
+        if !summary.reasons.is_empty() {
+            text.push_str("\n按原因：");
+            for (id, n) in summary.reasons.iter() {
+                let label = self.cfg.reason(id).map_or(id.as_str(), |r| r.label.as_str());
+                text.push_str(&format!("\n- {}：{}", escape_markdown(label), n));
+            }
+        }
+        if !summary.authors.is_empty() {
+            text.push_str("\n被标记最多的用户：");
+            for (i, (name, id, n)) in summary.authors.iter().enumerate() {
+                let id = id.map_or_else(|| "隐藏用户".to_string(), |id| id.to_string());
+                text.push_str(&format!("\n{}. {} ({})：{}", i + 1, escape_markdown(name), id, n));
+            }
+        }
+
+        let mut msg = SendMessage::new(ChatId::from(chat), text);
+        msg.parse_mode(ParseMode::Markdown);
+        msg.disable_notification();
+        let sent = self.api.send(msg).await?;
+
+        self.summary_done(scheduled);
+        if self.cfg.summary_pin {
+            if let MessageOrChannelPost::Message(sent) = sent {
+                self.pin_summary(&sent).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pin the summary in place of the previous one, failures are only
+    /// logged since the summary has been posted.
+    pub async fn pin_summary(&self, summary: &Message) {
+        let previous = match self.cache.borrow().pinned_summary() {
+            Ok(previous) => previous,
+            Err(err) => {
+                warn!("get pinned summary: {}", err);
+                None
+            }
+        };
+        if let Some((chat, id)) = previous {
+            if let Err(err) = self.api.send(UnpinMessage::new(chat, id)).await {
+                warn!("unpin summary {} in chat {}: {}", id, chat, err);
+            }
+        }
+
+        let mut pin = summary.pin();
+        pin.disable_notification();
+        if let Err(err) = self.api.send(pin).await {
+            warn!("pin summary {}, check the pin permission of bot: {}", summary.id, err);
+            return;
+        }
+        let pinned = self
+            .cache
+            .borrow_mut()
+            .set_pinned_summary((summary.chat.id(), summary.id));
+        if let Err(err) = pinned {
+            warn!("record pinned summary {}: {}", summary.id, err);
+        }
+    }
+
+    /// Save states of limiters if `persist_limits` is enabled.
+    pub fn save_limits(&self) -> Result<()> {
+        if !self.cfg.persist_limits {
+            return Ok(());
+        }
+
+        let now = now();
+        let mut cache = self.cache.borrow_mut();
+        cache.set_limits("cooldown", &self.cooldown.borrow().state(now))?;
+        cache.set_limits("user_reminders", &self.user_reminders.borrow().state(now))?;
+        cache.set_limits("stickers", &self.stickers.borrow().state(now))?;
+        cache.set_limits("sticker_alerts", &self.sticker_alerts.borrow().state(now))?;
+        Ok(())
+    }
+
+    /// Prune the next batch of cached messages, if a pass is going on.
+    pub fn prune_step(&self) -> Result<()> {
+        let mut pruning = self.pruning.borrow_mut();
+        let p = match pruning.as_mut() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let (removed, cursor) = self.cache.borrow_mut().prune_messages(
+            p.expired,
+            p.cursor.as_deref(),
+            self.cfg.prune_batch.max(1),
+        )?;
+        p.removed += removed;
+        p.batches += 1;
+        p.cursor = cursor;
+        if p.cursor.is_none() {
+            info!("pruned {} expired cached messages in {} batches", p.removed, p.batches);
+            *pruning = None;
+        } else {
+            debug!("pruned {} expired cached messages in {} batches so far", p.removed, p.batches);
+        }
+        Ok(())
+    }
+
+    /// Restore states of limiters saved before the restart.
+    pub fn restore_limits(&self) -> Result<()> {
+        let now = now();
+        let cache = self.cache.borrow();
+        self.cooldown.borrow_mut().restore(cache.limits("cooldown")?, now);
+        self.user_reminders
+            .borrow_mut()
+            .restore(cache.limits("user_reminders")?, now);
+        self.stickers.borrow_mut().restore(cache.limits("stickers")?, now);
+        self.sticker_alerts
+            .borrow_mut()
+            .restore(cache.limits("sticker_alerts")?, now);
+        Ok(())
+    }
+
+    pub fn snapshot_stats(&self) -> Result<()> {
+        let now = now();
+        let mut cache = self.cache.borrow_mut();
+
+        cache.set_snapshot(now, &self.stats.borrow())?;
+        if self.cfg.stats_retention > 0 {
+            let expired = now - self.cfg.stats_retention as Integer * 86400;
+            let removed = cache.prune_snapshots(expired)?;
+            debug!("pruned {} expired stats snapshots", removed);
+        }
+
+        let before = |days: u64| match days {
+            0 => None,
+            days => Some(now - days as Integer * 86400),
+        };
+        // A pass over messages still going on will prune these as well.
+        if self.cfg.cache_retention > 0 && self.pruning.borrow().is_none() {
+            let expired = now - self.cfg.cache_retention as Integer * 86400;
+            *self.pruning.borrow_mut() = Some(Pruning {
+                expired,
+                cursor: None,
+                removed: 0,
+                batches: 0,
+            });
+        }
+
+        if self.cfg.history_retention > 0 {
+            let expired = now - self.cfg.history_retention as Integer * 86400;
+            let removed = cache.prune_history(expired)?;
+            debug!("pruned {} expired histories", removed);
+        }
+
+        let removed = cache.prune_flags(
+            before(self.cfg.resolved_retention),
+            before(self.cfg.open_retention),
+        )?;
+        debug!("pruned {} expired flags", removed);
+
+        Ok(())
+    }
+}
+
+/// Apply `extra_admins` and `excluded_admins` to fetched admins.
+fn override_admins(cfg: &config::Config, admins: &mut HashSet<UserId>) {
+    admins.extend(cfg.extra_admins.iter().map(|id| UserId::from(*id)));
+    for id in cfg.excluded_admins.iter() {
+        admins.remove(&UserId::from(*id));
+    }
+    admins.extend(cfg.bootstrap_admins.iter().map(|id| UserId::from(*id)));
+}
+
+fn warn_unusable_admins(cfg: &config::Config, admins: &[ChatMember]) {
+    if admins.iter().all(|m| m.user.is_bot) {
+        warn!(
+            "groups {:?} have no human admins, only bootstrap admins {:?} can use the bot",
+            cfg.admin_groups(),
+            cfg.bootstrap_admins
+        );
+    }
+}
+
+/// Fetch admins of all groups in `admin_source`.
+async fn fetch_admins(
+    api: &budget::Client,
+    cfg: &config::Config,
+) -> Result<Vec<ChatMember>, telegram_bot::Error> {
+    let mut admins = Vec::new();
+    for group in cfg.admin_groups() {
+        admins.extend(api.send(GetChatAdministrators::new(ChatId::from(group))).await?);
+    }
+    Ok(admins)
+}
+
+/// Returns a short random code for confirming commands.
+fn confirm_code() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos()),
+    );
+    format!("{:06x}", hasher.finish() & 0xff_ffff)
+}
+
+/// Extract command from a message starts with bot command.
+fn parse_command(kind: &MessageKind) -> Option<Command> {
+    let (data, entities) = match kind {
+        MessageKind::Text { data, entities } => (data, entities),
+        _ => return None,
+    };
+    if !entities
+        .iter()
+        .any(|e| e.kind == BotCommand && e.offset == 0)
+    {
+        return None;
+    }
+
+    command::split(data)
+}
+
+/// Name of the original sender shown in prompts.
+fn forward_author(from: &ForwardFrom) -> String {
+    match from {
+        ForwardFrom::User { user } if is_deleted_account(user) => "已注销账号".to_string(),
+        ForwardFrom::User { user } => full_name(user),
+        ForwardFrom::ChannelHiddenUser { sender_name } => sender_name.clone(),
+        ForwardFrom::Channel { channel, .. } => channel.title.clone(),
+    }
+}
+
+/// Returns the forwarded message that the callback's prompt replied to.
+fn forwarded_message(c: &CallbackQuery) -> Option<&Message> {
+    let prompt = match c.message.as_ref()? {
+        MessageOrChannelPost::Message(m) => m,
+        _ => return None,
+    };
+    match prompt.reply_to_message.as_deref()? {
+        MessageOrChannelPost::Message(m) => Some(m),
+        _ => None,
+    }
+}
+
+/// Check whether the text differs from the cached one, messages cached
+/// without a hash are taken as changed.
+/// Text of the edited message if it's checked again, that is it's edited
+/// within `window` seconds after being sent and its text has changed.
+fn edit_to_recheck<'a>(
+    m: &'a Message,
+    cached: Option<&cache::CachedMessage>,
+    window: Integer,
+    now: Integer,
+) -> Option<&'a String> {
+    if m.edit_date.unwrap_or(now) - m.date > window {
+        debug!("message {} is edited too late, skip checks", m.id);
+        return None;
+    }
+    let text = message_text(m)?;
+    // Edits of formatting or of other fields don't change the text.
+    if !text_changed(cached, text) {
+        return None;
+    }
+    Some(text)
+}
+
+fn text_changed(cached: Option<&cache::CachedMessage>, text: &str) -> bool {
+    cached.and_then(|m| m.hash) != Some(cache::text_hash(text))
+}
+
+/// The original message flagged in the callback, as recorded in stats.
+fn flagged_item(c: &CallbackQuery, id: MessageId) -> cache::BatchItem {
+    let forwarded = forwarded_message(c);
+    let (author, author_name, deleted) = match forwarded.and_then(|m| m.forward.as_ref()) {
+        Some(Forward {
+            from: ForwardFrom::User { user },
+            ..
+        }) => (Some(user.id), full_name(user), is_deleted_account(user)),
+        Some(Forward {
+            from: ForwardFrom::ChannelHiddenUser { sender_name },
+            ..
+        }) => (None, sender_name.clone(), false),
+        _ => (None, "未知用户".to_string(), false),
+    };
+    cache::BatchItem {
+        id,
+        kind: forwarded.map_or(config::ContentKind::Text, content_kind),
+        author,
+        author_name,
+        deleted,
+    }
+}
+
+const SUMMARY_LEN: usize = 30;
+
+/// Summarize message content in a short line, media without caption
+/// will be labeled by its kind.
+fn summarize(m: &Message, normalized: bool) -> String {
+    let label = match &m.kind {
+        MessageKind::Photo { .. } => "[图片]",
+        MessageKind::Video { .. } => "[视频]",
+        MessageKind::Document { .. } => "[文件]",
+        MessageKind::Sticker { .. } => "[贴纸]",
+        MessageKind::Audio { .. } => "[音频]",
+        MessageKind::Voice { .. } => "[语音]",
+        MessageKind::VideoNote { .. } => "[视频消息]",
+        MessageKind::Contact { .. } => "[联系人]",
+        MessageKind::Location { .. } => "[位置]",
+        MessageKind::Venue { .. } => "[地点]",
+        MessageKind::Poll { .. } => "[投票]",
+        _ => "[消息]",
+    };
+
+    let text = match message_text(m) {
+        None => return label.to_string(),
+        Some(text) if normalized => normalize(text),
+        Some(text) => text.clone(),
+    };
+    if text.chars().count() > SUMMARY_LEN {
+        let mut s: String = text.chars().take(SUMMARY_LEN).collect();
+        s.push('…');
+        return s;
+    }
+    text
+}
+
+/// Returns the text covered by the entity, whose offset and length count
+/// UTF-16 code units.
+fn entity_text(text: &str, e: &MessageEntity) -> String {
+    let units: Vec<u16> = text
+        .encode_utf16()
+        .skip(e.offset as usize)
+        .take(e.length as usize)
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// Count links in text or caption of the message.
+fn count_links(m: &Message) -> usize {
+    match &m.kind {
+        MessageKind::Text { entities, .. } => entities
+            .iter()
+            .filter(|e| matches!(e.kind, MessageEntityKind::Url | MessageEntityKind::TextLink(_)))
+            .count(),
+        // Captions carry no entities here, look for links in words.
+        _ => message_text(m).map_or(0, |text| {
+            text.split_whitespace()
+                .filter(|w| {
+                    w.starts_with("http://") || w.starts_with("https://") || w.starts_with("t.me/")
+                })
+                .count()
+        }),
+    }
+}
+
+fn content_kind(m: &Message) -> config::ContentKind {
+    match &m.kind {
+        MessageKind::Text { .. } => config::ContentKind::Text,
+        MessageKind::Sticker { .. } => config::ContentKind::Sticker,
+        // Telegram converts GIFs to mp4 and sends them as documents.
+        MessageKind::Document { data, .. }
+            if matches!(data.mime_type.as_deref(), Some("video/mp4") | Some("image/gif")) =>
+        {
+            config::ContentKind::Animation
+        }
+        MessageKind::Photo { .. } | MessageKind::Video { .. } | MessageKind::Document { .. } => {
+            config::ContentKind::Media
+        }
+        _ => config::ContentKind::Other,
+    }
+}
+
+/// Returns text of the message or caption of the media.
+fn message_text(m: &Message) -> Option<&String> {
+    match &m.kind {
+        MessageKind::Text { data, .. } => Some(data),
+        MessageKind::Photo { caption, .. }
+        | MessageKind::Video { caption, .. }
+        | MessageKind::Document { caption, .. } => caption.as_ref(),
+        _ => None,
+    }
+}
+
+/// Check whether the url looks like one telegram accepts for url buttons.
+fn is_button_url(url: &str) -> bool {
+    let rest = match ["https://", "http://", "tg://"]
+        .iter()
+        .find_map(|scheme| url.strip_prefix(scheme))
+    {
+        Some(rest) => rest,
+        None => return false,
+    };
+    !rest.is_empty() && !rest.starts_with('/') && !url.chars().any(char::is_whitespace)
+}
+
+// telegram-bot doesn't parse the restricted status.
+fn is_restricted(member: &ChatMember) -> bool {
+    matches!(&member.status, ChatMemberStatus::Unknown(status) if status == "restricted")
+}
+
+fn update_kind(kind: &UpdateKind) -> &'static str {
+    match kind {
+        UpdateKind::Message(_) => "message",
+        UpdateKind::EditedMessage(_) => "edited_message",
+        UpdateKind::ChannelPost(_) => "channel_post",
+        UpdateKind::EditedChannelPost(_) => "edited_channel_post",
+        UpdateKind::InlineQuery(_) => "inline_query",
+        UpdateKind::CallbackQuery(_) => "callback_query",
+        UpdateKind::Poll(_) => "poll",
+        UpdateKind::PollAnswer(_) => "poll_answer",
+        UpdateKind::Error(_) => "error",
+        _ => "unknown",
+    }
+}
+
+/// Returns the code of the error if telegram returned it.
+fn error_code(err: &anyhow::Error) -> Option<Integer> {
+    err.downcast_ref::<ApiError>().map(|err| err.code)
+}
+
+/// Describe the update without any text or names for `privacy_mode`.
+fn redacted_update(u: &Update) -> String {
+    let kind = update_kind(&u.kind);
+    match &u.kind {
+        UpdateKind::Message(m) | UpdateKind::EditedMessage(m) => format!(
+            "update {}: {} {} in chat {} from user {}, {:?} of {} chars",
+            u.id,
+            kind,
+            m.id,
+            m.chat.id(),
+            m.from.id,
+            content_kind(m),
+            message_text(m).map_or(0, |text| text.chars().count())
+        ),
+        UpdateKind::ChannelPost(p) | UpdateKind::EditedChannelPost(p) => {
+            format!("update {}: {} {} in chat {}", u.id, kind, p.id, p.chat.id)
+        }
+        UpdateKind::CallbackQuery(c) => format!(
+            "update {}: {} {:?} from user {}",
+            u.id, kind, c.id, c.from.id
+        ),
+        _ => format!("update {} of kind {}", u.id, kind),
+    }
+}
+
+/// Check whether the update falls in the sample, ids are scrambled first
+/// since consecutive ids would otherwise be sampled in runs.
+fn is_sampled(id: Integer, rate: f64) -> bool {
+    // Knuth's multiplicative hash.
+    let hash = (id as u32).wrapping_mul(2_654_435_761);
+    (hash as f64) < rate * (u32::MAX as f64 + 1.0)
+}
+
+/// Sender of the message replied to by the command, or of the message it
+/// forwards. Alerts and prompts of the bot aren't targets.
+fn replied_user(m: &Message, me: UserId, spec: &command::Spec) -> Result<UserId> {
+    let target = match m.reply_to_message.as_deref() {
+        Some(MessageOrChannelPost::Message(target)) => target,
+        _ => {
+            return Err(anyhow!(
+                "请回复要处理的消息后再使用此命令\n用法：{}",
+                spec.usage()
+            ))
+        }
+    };
+    let user = match &target.forward {
+        Some(Forward {
+            from: ForwardFrom::User { user },
+            ..
+        }) => user,
+        Some(_) => return Err(anyhow!("无法识别所回复消息的发送者，请指定用户 ID")),
+        None if target.from.id == me => {
+            return Err(anyhow!("请回复要处理的消息后再使用此命令\n用法：{}", spec.usage()))
+        }
+        None => &target.from,
+    };
+    if is_deleted_account(user) {
+        return Err(anyhow!("所回复消息的发送者已注销账号，无需处理"));
+    }
+    Ok(user.id)
+}
+
+/// The command with the user put before its arguments, as if it was given.
+fn with_target(cmd: &Command, user: UserId) -> Command {
+    Command {
+        rest: format!("{} {}", user, cmd.rest),
+        ..cmd.clone()
+    }
+}
+
+/// Parse `/start` from appeal buttons, returns the appealed message if the
+/// appeal is from an alert.
+fn appeal_start(cmd: &command::Command) -> Option<Option<MessageId>> {
+    if cmd.name != "start" {
+        return None;
+    }
+    if cmd.rest == APPEAL_START {
+        return Some(None);
+    }
+    let id = cmd.rest.strip_prefix(APPEAL_START)?.strip_prefix('_')?;
+    id.parse::<Integer>().ok().map(|id| Some(MessageId::new(id)))
+}
+
+fn appeal_label(status: cache::AppealStatus) -> &'static str {
+    match status {
+        cache::AppealStatus::Open => "待处理",
+        cache::AppealStatus::Reviewing => "处理中",
+        cache::AppealStatus::Resolved => "已解决",
+    }
+}
+
+fn media_group(m: &Message) -> Option<&String> {
+    match &m.kind {
+        MessageKind::Photo { media_group_id, .. } | MessageKind::Video { media_group_id, .. } => {
+            media_group_id.as_ref()
+        }
+        _ => None,
+    }
+}
+
+fn format_time(time: Integer) -> String {
+    match Utc.timestamp_opt(time, 0).single() {
+        Some(t) => t.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        None => time.to_string(),
+    }
+}
+
+/// Returns the timezone `offset` minutes east of UTC.
+fn timezone(offset: i32) -> Option<FixedOffset> {
+    FixedOffset::east_opt(offset.checked_mul(60)?)
+}
+
+/// Parse time of day in `HH:MM`.
+fn parse_time_of_day(s: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(s, "%H:%M").ok()
+}
+
+/// Build a link to a message in supergroup.
+fn message_link(chat: i64, id: MessageId) -> String {
+    // Supergroup ids are prefixed with -100 which is not a part of the link.
+    let chat = chat.to_string();
+    format!(
+        "https://t.me/c/{}/{}",
+        chat.trim_start_matches("-100"),
+        id
+    )
+}
+
+/// Check whether the user has deleted their account, telegram keeps only
+/// the id and a placeholder name of such users.
+fn is_deleted_account(user: &User) -> bool {
+    user.first_name == "Deleted Account" && user.last_name.is_none() && user.username.is_none()
+}
+
+fn full_name(user: &User) -> String {
+    let mut name = user.first_name.clone();
+    if let Some(last_name) = &user.last_name {
+        name.push(' ');
+        name.push_str(last_name);
+    }
+    name
+}
+
+fn escape_markdown(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '_' | '*' | '`' | '[') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn now() -> Integer {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as Integer)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached(text: Option<&str>) -> cache::CachedMessage {
+        cache::CachedMessage {
+            id: MessageId::from(1),
+            text: None,
+            hash: text.map(cache::text_hash),
+        }
+    }
+
+    #[test]
+    fn text_changed_by_edit() {
+        assert!(!text_changed(Some(&cached(Some("hello"))), "hello"));
+        assert!(text_changed(Some(&cached(Some("hello"))), "buy now"));
+        // Nothing to compare with, check again to be safe.
+        assert!(text_changed(Some(&cached(None)), "hello"));
+        assert!(text_changed(None, "hello"));
+    }
+
+    fn edited(text: &str, edit_date: Integer) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 1,
+            "date": 100,
+            "edit_date": edit_date,
+            "chat": {"id": -1, "type": "supergroup", "title": "main"},
+            "from": {"id": 3, "is_bot": false, "first_name": "spammer"},
+            "text": text,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn recheck_edits_into_spam() {
+        let innocent = cached(Some("hello everyone"));
+        let spam = config::Filter {
+            keyword: "spam".to_string(),
+            fuzzy: true,
+            reason: "spam".to_string(),
+        };
+        assert!(!filter::matches(&spam, "hello everyone"));
+
+        let m = edited("buy sp4m now", 160);
+        let text = edit_to_recheck(&m, Some(&innocent), 60, 1000).unwrap();
+        assert!(filter::matches(&spam, text));
+
+        // Edited after the window, or only in formatting.
+        assert!(edit_to_recheck(&edited("buy sp4m now", 161), Some(&innocent), 60, 1000).is_none());
+        assert!(edit_to_recheck(&edited("hello everyone", 120), Some(&innocent), 60, 1000).is_none());
+    }
+
+    fn message(reply: Option<serde_json::Value>) -> Message {
+        let mut m = serde_json::json!({
+            "message_id": 2,
+            "date": 0,
+            "chat": {"id": -1, "type": "supergroup", "title": "main"},
+            "from": {"id": 1, "is_bot": false, "first_name": "admin"},
+            "text": "/mute 5m",
+        });
+        if let Some(reply) = reply {
+            m["reply_to_message"] = reply;
+        }
+        serde_json::from_value(m).unwrap()
+    }
+
+    fn reply(from: Integer, first_name: &str) -> serde_json::Value {
+        serde_json::json!({
+            "message_id": 1,
+            "date": 0,
+            "chat": {"id": -1, "type": "supergroup", "title": "main"},
+            "from": {"id": from, "is_bot": false, "first_name": first_name},
+            "text": "spam",
+        })
+    }
+
+    #[test]
+    fn reply_target_of_commands() {
+        let me = UserId::from(99);
+        let specs: Vec<_> = command::COMMANDS.iter().filter(|s| s.reply_target).collect();
+        assert!(!specs.is_empty());
+
+        for spec in specs {
+            let err = replied_user(&message(None), me, spec).unwrap_err();
+            assert!(err.to_string().contains(&spec.usage()), "{}", spec.name);
+            assert!(replied_user(&message(Some(reply(99, "onobot"))), me, spec).is_err());
+            assert!(replied_user(&message(Some(reply(3, "Deleted Account"))), me, spec).is_err());
+
+            let user = replied_user(&message(Some(reply(3, "spammer"))), me, spec).unwrap();
+            assert_eq!(user, UserId::from(3));
+            // Arguments after the user id, sent without the user id.
+            let rest = if spec.name == "mute" { "5m" } else { "" };
+            let cmd = command::split(&format!("/{} {}", spec.name, rest)).unwrap();
+            assert!(spec.parse(&cmd.rest).is_err(), "{}", spec.name);
+            let values = spec.parse(&with_target(&cmd, user).rest).unwrap();
+            assert_eq!(values[0], Value::UserId(UserId::from(3)), "{}", spec.name);
+        }
+
+        let mute = command::find("mute").unwrap();
+        let cmd = command::split("/mute 5m").unwrap();
+        assert_eq!(
+            mute.parse(&with_target(&cmd, UserId::from(3)).rest).unwrap(),
+            vec![Value::UserId(UserId::from(3)), Value::Duration(300)]
+        );
+
+        let mut forwarded = reply(99, "onobot");
+        forwarded["forward_from"] = serde_json::json!({"id": 4, "is_bot": false, "first_name": "author"});
+        forwarded["forward_date"] = serde_json::json!(0);
+        assert_eq!(replied_user(&message(Some(forwarded)), me, mute).unwrap(), UserId::from(4));
+    }
+
+    #[test]
+    fn callbacks_fit_in_callback_data() {
+        // Longest values of every variant, with a reason id of usual length.
+        let reason = "offtopic".to_string();
+        let callbacks = [
+            Callback::Reason {
+                id: MessageId::from(Integer::from(i32::MAX)),
+                reason: reason.clone(),
+            },
+            Callback::ConfirmReason {
+                id: MessageId::from(Integer::from(i32::MAX)),
+                reason: reason.clone(),
+            },
+            Callback::AdminsPage {
+                group: -1_009_999_999_999,
+                offset: u32::MAX as usize,
+            },
+            Callback::Menu {
+                action: MenuAction::ReloadAdmins,
+            },
+            Callback::Batch {
+                id: u64::from(u32::MAX),
+                reason: reason.clone(),
+            },
+            Callback::ConfirmBatch {
+                id: u64::from(u32::MAX),
+                reason,
+            },
+            Callback::Appeal {
+                id: u64::from(u32::MAX),
+                status: cache::AppealStatus::Reviewing,
+            },
+            Callback::JoinDecision {
+                user: UserId::from(9_999_999_999),
+                approved: false,
+            },
+        ];
+        for format in [config::CallbackFormat::Json, config::CallbackFormat::Compact].iter() {
+            for cb in callbacks.iter() {
+                let data = cb.encode(*format).unwrap();
+                assert!(data.len() <= MAX_CALLBACK_DATA_LEN, "{:?}", cb);
+                assert_eq!(Callback::decode(&data).unwrap().kind(), cb.kind());
+            }
+        }
+    }
 }
@@ -0,0 +1,139 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use futures::{stream, Stream};
+use log::warn;
+use telegram_bot::{Api, Integer, Request, ResponseType};
+use tokio::time;
+
+use super::requests::{GetRawUpdates, RawUpdate};
+
+/// Token bucket shared by all outbound API calls.
+///
+/// Calls over the budget are delayed until tokens are refilled instead of
+/// being dropped. A zero rate never delays.
+pub struct Budget {
+    // Tokens refilled per second.
+    rate: f64,
+    burst: f64,
+    // Tokens left, negative once calls are queued.
+    tokens: f64,
+    last: Instant,
+    /// Calls made since startup.
+    pub calls: u64,
+    /// Calls that had to wait for tokens.
+    pub delayed: u64,
+}
+
+impl Budget {
+    /// Allow `per_minute` calls per minute on average and `burst` calls at
+    /// once.
+    pub fn new(per_minute: u64, burst: u64) -> Self {
+        let burst = burst.max(1) as f64;
+        Budget {
+            rate: per_minute as f64 / 60.0,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+            calls: 0,
+            delayed: 0,
+        }
+    }
+
+    /// Reserve a token for a call, returns how long the call has to wait.
+    pub fn reserve(&mut self, now: Instant) -> Duration {
+        self.calls += 1;
+        if self.rate <= 0.0 {
+            return Duration::from_secs(0);
+        }
+
+        self.refill(now);
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            return Duration::from_secs(0);
+        }
+        self.delayed += 1;
+        Duration::from_secs_f64(-self.tokens / self.rate)
+    }
+
+    /// Fraction of the burst that is used up, above 1 if calls are queued.
+    pub fn utilization(&mut self, now: Instant) -> f64 {
+        if self.rate <= 0.0 {
+            return 0.0;
+        }
+        self.refill(now);
+        1.0 - self.tokens / self.burst
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+}
+
+/// Telegram API client that draws every request from the budget.
+pub struct Client {
+    api: Api,
+    budget: RefCell<Budget>,
+}
+
+impl Client {
+    pub fn new(api: Api, budget: Budget) -> Self {
+        Client {
+            api,
+            budget: RefCell::new(budget),
+        }
+    }
+
+    /// Long polling for updates doesn't draw from the budget. Errors of
+    /// polls are returned as `ApiError` if telegram returned them, updates
+    /// that can't be parsed are skipped.
+    pub fn updates(&self, timeout: Duration) -> impl Stream<Item = anyhow::Result<RawUpdate>> {
+        let state = (self.api.clone(), 0, VecDeque::new());
+        stream::unfold(state, move |(api, mut offset, mut buffer)| async move {
+            loop {
+                if let Some(update) = buffer.pop_front() {
+                    return Some((Ok(update), (api, offset, buffer)));
+                }
+                let poll = GetRawUpdates::new(offset, timeout.as_secs() as Integer);
+                // Telegram may hold the poll a bit longer than asked.
+                let updates = match api.send_timeout(poll, timeout + Duration::from_secs(1)).await {
+                    Ok(Some(Ok(updates))) => updates,
+                    Ok(None) => continue,
+                    Ok(Some(Err(err))) => return Some((Err(err.into()), (api, offset, buffer))),
+                    Err(err) => return Some((Err(err.into()), (api, offset, buffer))),
+                };
+                for value in updates {
+                    // Skip updates even if they can't be parsed.
+                    if let Some(id) = value.get("update_id").and_then(|id| id.as_i64()) {
+                        offset = offset.max(id + 1);
+                    }
+                    match RawUpdate::parse(value) {
+                        Ok(update) => buffer.push_back(update),
+                        Err(err) => warn!("parse update: {}", err),
+                    }
+                }
+            }
+        })
+    }
+
+    pub async fn send<Req: Request>(
+        &self,
+        request: Req,
+    ) -> Result<<Req::Response as ResponseType>::Type, telegram_bot::Error> {
+        let wait = self.budget.borrow_mut().reserve(Instant::now());
+        if wait > Duration::from_secs(0) {
+            time::delay_for(wait).await;
+        }
+        self.api.send(request).await
+    }
+
+    /// Returns calls made, calls delayed and the current utilization.
+    pub fn usage(&self) -> (u64, u64, f64) {
+        let mut budget = self.budget.borrow_mut();
+        let utilization = budget.utilization(Instant::now());
+        (budget.calls, budget.delayed, utilization)
+    }
+}
@@ -1,35 +1,1140 @@
-use log::debug;
+use std::ops::Bound;
+
+use log::{debug, warn};
 use telegram_bot::*;
 use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use super::codec::{decode, Format};
+use super::config::ContentKind;
+use super::deadletter::DeadLetter;
+use super::requests::ChatPermissions;
+use super::stats::{Counters, Flag};
+
+const TRUNCATED: &str = "…（已截断）";
+// Version of value layouts in the cache, bumped whenever entries written
+// before need `migrate`.
+const SCHEMA_VERSION: u32 = 2;
+// Trees holding entries in old layouts, in the order they're migrated.
+// Messages are in the default tree.
+const MIGRATED_TREES: &[&str] = &["messages", "flags"];
+// Entries migrated between saves of the progress.
+const MIGRATION_BATCH: usize = 1000;
+
+/// Persistent state of the bot.
+///
+/// Reads and writes return errors instead of panicking. Writes of bans are
+/// critical: failures are reported to admins, since banned users would be
+/// missing from `ignore_banned_commands` and summaries otherwise, the same
+/// as audits that can't be sent. Other writes are best-effort: callers log
+/// failures and go on, failed flags are told to the admin acting on them.
+pub struct Cache {
+    db: sled::Db,
+    max_text_len: usize,
+    date_tolerance: Integer,
+    format: Format,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CachedMessage {
+    pub id: MessageId,
+    /// Text or caption of the message, only present if text caching is
+    /// enabled. Text longer than `max_cached_text_len` is truncated.
+    pub text: Option<String>,
+    /// `text_hash` of the full text or caption, so that edits leaving it
+    /// unchanged can be told apart even if text isn't cached.
+    #[serde(default)]
+    pub hash: Option<u64>,
+}
+
+/// Hash of the text kept in cache, stable across builds unlike std hashers.
+pub fn text_hash(text: &str) -> u64 {
+    // FNV-1a.
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Result of `Cache::migrate`.
+#[derive(Debug, Default)]
+pub struct Migration {
+    pub scanned: usize,
+    pub upgraded: usize,
+    pub discarded: usize,
+    /// Entries that can't be read in any known layout, left as is.
+    pub unreadable: usize,
+}
+
+// Layout of an entry found by `migrate`.
+enum Entry {
+    Current,
+    /// An entry in an old layout, along with it encoded in the current one.
+    Old(Vec<u8>),
+    Unreadable,
+}
+
+/// Rules of main group synced from its pinned message by `/syncrules`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rules {
+    pub id: MessageId,
+    pub text: String,
+    pub time: Integer,
+}
 
-pub struct Cache(sled::Db);
+/// A mute by `/mute`, holding what to restore once it's lifted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Mute {
+    pub until: Integer,
+    /// Permissions of the member before the mute.
+    pub permissions: ChatPermissions,
+    /// When the restrictions before the mute would have been lifted.
+    pub permissions_until: Option<Integer>,
+}
+
+/// An appeal sent in private chat.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Appeal {
+    pub user: UserId,
+    pub user_name: String,
+    pub text: String,
+    pub time: Integer,
+    /// Message whose alert is appealed, if the appeal started from it.
+    pub message: Option<MessageId>,
+    /// Record of the appeal in `appeal_queue`, `None` if it's not set.
+    pub record: Option<(ChatId, MessageId)>,
+    pub status: AppealStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum AppealStatus {
+    Open,
+    Reviewing,
+    Resolved,
+}
+
+/// An alert posted in main group, kept so that appeals can be reflected
+/// in it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Alert {
+    pub chat: ChatId,
+    pub id: MessageId,
+    pub reason: String,
+}
+
+/// Forwards asked about in a single prompt, see `batch_window`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Batch {
+    pub prompt: (ChatId, MessageId),
+    pub items: Vec<BatchItem>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchItem {
+    /// Id of the original message.
+    pub id: MessageId,
+    pub kind: ContentKind,
+    pub author: Option<UserId>,
+    pub author_name: String,
+    /// Deleted accounts can't be reminded.
+    pub deleted: bool,
+}
 
 impl Cache {
     pub fn new<P: AsRef<std::path::Path>>(path: P) -> Result<Cache> {
         let db = sled::open(path)?;
-        Ok(Self(db))
+        Ok(Self::from_db(db))
     }
 
-    pub fn get(&mut self, time: Integer, user_name: String) -> Option<MessageId> {
-        let key = format!("{}/{}", time, user_name);
-        let value = self.0.get(&key).expect("read from cache failed");
-        if value.is_none() {
-            debug!("cache not exist: {}", &key);
-            return None;
+    /// Open an in-memory cache that will be dropped on exit.
+    pub fn temporary() -> Result<Cache> {
+        let db = sled::Config::new().temporary(true).open()?;
+        Ok(Self::from_db(db))
+    }
+
+    fn from_db(db: sled::Db) -> Cache {
+        Self {
+            db,
+            max_text_len: usize::MAX,
+            date_tolerance: 0,
+            format: Format::default(),
         }
-        let id: i64 = bincode::deserialize(&value.unwrap().to_vec()).expect("invalid value");
-        debug!("cache get: {}, {}", &key, id);
+    }
 
-        Some(MessageId::from(id))
+    /// Encode values written from now on in `format`, values already written
+    /// in other formats can still be read.
+    pub fn format(&mut self, format: Format) -> &mut Self {
+        self.format = format;
+        self
     }
 
-    pub fn set(&mut self, time: Integer, user_name: String, m: MessageId) {
-        // TODO: remove old messages.
+    /// Limit the length in chars of text stored by `set`.
+    pub fn max_text_len(&mut self, len: usize) -> &mut Self {
+        self.max_text_len = len;
+        self
+    }
+
+    /// Allow `get` to match messages sent up to `secs` seconds away, since
+    /// forward date could be skewed from the original message date.
+    pub fn date_tolerance(&mut self, secs: Integer) -> &mut Self {
+        self.date_tolerance = secs;
+        self
+    }
+
+    /// Upgrade entries written in old layouts, or remove them if `discard`
+    /// is set. Returns `None` if the cache is up to date already.
+    ///
+    /// Progress is saved along the way so that an interrupted migration
+    /// resumes where it stopped, and entries in the current layout are left
+    /// alone so that migrating them again is harmless.
+    pub fn migrate(&mut self, discard: bool) -> Result<Option<Migration>> {
+        let meta = self.db.open_tree("meta")?;
+        let version: u32 = match meta.get("schema")? {
+            Some(v) => decode(&v)?,
+            None => 0,
+        };
+        if version >= SCHEMA_VERSION {
+            return Ok(None);
+        }
+        let cursor: Option<(String, Vec<u8>)> = match meta.get("migration")? {
+            Some(v) => Some(decode(&v)?),
+            None => None,
+        };
+        let resume = cursor
+            .as_ref()
+            .and_then(|(tree, _)| MIGRATED_TREES.iter().position(|name| name == tree));
+
+        let mut m = Migration::default();
+        for (i, name) in MIGRATED_TREES.iter().enumerate() {
+            let start = match (&cursor, resume) {
+                (_, Some(r)) if i < r => continue,
+                (Some((_, key)), Some(r)) if i == r => Bound::Excluded(key.clone()),
+                _ => Bound::Unbounded,
+            };
+            let tree = match *name {
+                "messages" => (*self.db).clone(),
+                name => self.db.open_tree(name)?,
+            };
+            for kv in tree.range::<Vec<u8>, _>((start, Bound::Unbounded)) {
+                let (k, v) = kv?;
+                match self.entry(name, &v)? {
+                    Entry::Current => {}
+                    Entry::Old(_) if discard => {
+                        tree.remove(&k)?;
+                        m.discarded += 1;
+                    }
+                    Entry::Old(upgraded) => {
+                        tree.insert(&k, upgraded)?;
+                        m.upgraded += 1;
+                    }
+                    Entry::Unreadable => {
+                        warn!("cache entry {:?} in {} is unreadable", k, name);
+                        m.unreadable += 1;
+                    }
+                }
+                m.scanned += 1;
+                if m.scanned % MIGRATION_BATCH == 0 {
+                    meta.insert("migration", self.format.encode(&(name, k.to_vec()))?)?;
+                }
+            }
+        }
+
+        meta.insert("schema", self.format.encode(&SCHEMA_VERSION)?)?;
+        meta.remove("migration")?;
+        Ok(Some(m))
+    }
+
+    fn entry(&self, tree: &str, v: &[u8]) -> Result<Entry> {
+        Ok(match tree {
+            "messages" => {
+                if decode::<CachedMessage>(v).is_ok() {
+                    return Ok(Entry::Current);
+                }
+                match decode_message(v) {
+                    Ok(m) => Entry::Old(self.format.encode(&m)?),
+                    Err(_) => Entry::Unreadable,
+                }
+            }
+            _ => {
+                if decode::<Flag>(v).is_ok() {
+                    return Ok(Entry::Current);
+                }
+                match decode_flag(v) {
+                    Ok(f) => Entry::Old(self.format.encode(&f)?),
+                    Err(_) => Entry::Unreadable,
+                }
+            }
+        })
+    }
+
+    /// Write all pending changes to disk.
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Get cached message at `time`, or the nearest one within date tolerance.
+    pub fn get(&mut self, time: Integer, user_name: String) -> Result<Option<CachedMessage>> {
+        for delta in 0..=self.date_tolerance {
+            if let Some(m) = self.get_exact(time - delta, &user_name)? {
+                return Ok(Some(m));
+            }
+            if delta == 0 {
+                continue;
+            }
+            if let Some(m) = self.get_exact(time + delta, &user_name)? {
+                return Ok(Some(m));
+            }
+        }
+        Ok(None)
+    }
+
+    fn get_exact(&self, time: Integer, user_name: &str) -> Result<Option<CachedMessage>> {
+        let key = format!("{}/{}", time, user_name);
+        let value = match self.db.get(&key)? {
+            Some(value) => value,
+            None => {
+                debug!("cache not exist: {}", &key);
+                return Ok(None);
+            }
+        };
+        let m = decode_message(&value)?;
+        debug!("cache get: {}, {}", &key, m.id);
+
+        Ok(Some(m))
+    }
+
+    pub fn set(&mut self, time: Integer, user_name: String, mut m: CachedMessage) -> Result<()> {
         let key = format!("{}/{}", time, user_name);
-        debug!("cache set: {}, {}", &key, &m);
-        self.0.insert(
-            &key,
-            bincode::serialize(&m).expect("bincode serialize failed"),
-        ).expect("write into cache failed");
+        debug!("cache set: {}, {}", &key, &m.id);
+        if let Some(text) = &mut m.text {
+            if text.chars().count() > self.max_text_len {
+                *text = text.chars().take(self.max_text_len).collect();
+                text.push_str(TRUNCATED);
+            }
+        }
+        self.db.insert(&key, self.format.encode(&m)?)?;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, time: Integer, user_name: &str) -> Result<()> {
+        self.db.remove(format!("{}/{}", time, user_name))?;
+        Ok(())
+    }
+
+    /// Removes cached messages sent before `time` among at most `limit`
+    /// messages after `cursor`, returning how many were removed and the
+    /// cursor to continue from, `None` once all messages are scanned.
+    pub fn prune_messages(
+        &mut self,
+        time: Integer,
+        cursor: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<(usize, Option<Vec<u8>>)> {
+        let start = match cursor {
+            Some(cursor) => Bound::Excluded(cursor.to_vec()),
+            None => Bound::Unbounded,
+        };
+        let mut removed = 0;
+        let mut scanned = 0;
+        for kv in self.db.range::<Vec<u8>, _>((start, Bound::Unbounded)) {
+            let (k, _) = kv?;
+            // Keys are `{time}/{user_name}`, so they are not ordered by time.
+            let sent = String::from_utf8_lossy(&k)
+                .split('/')
+                .next()
+                .and_then(|t| t.parse::<Integer>().ok());
+            if sent.is_some_and(|t| t < time) {
+                self.db.remove(&k)?;
+                removed += 1;
+            }
+            scanned += 1;
+            if scanned >= limit {
+                return Ok((removed, Some(k.to_vec())));
+            }
+        }
+        Ok((removed, None))
+    }
+
+    // Snapshots are keyed by big-endian unix time so that sled keeps them
+    // ordered and range scans by time work.
+    pub fn set_snapshot(&mut self, time: Integer, c: &Counters) -> Result<()> {
+        debug!("snapshot set: {}, {:?}", time, c);
+        self.db
+            .open_tree("stats")?
+            .insert(time.to_be_bytes(), self.format.encode(c)?)?;
+        Ok(())
+    }
+
+    pub fn latest_snapshot(&self) -> Result<Option<(Integer, Counters)>> {
+        match self.db.open_tree("stats")?.last()? {
+            None => Ok(None),
+            Some((k, v)) => Ok(Some(decode_snapshot(&k, &v)?)),
+        }
+    }
+
+    /// Returns the latest snapshot taken at or before `time`, falling back to
+    /// the oldest snapshot if all of them are newer.
+    pub fn snapshot_at(&self, time: Integer) -> Result<Option<(Integer, Counters)>> {
+        let tree = self.db.open_tree("stats")?;
+        let found = match tree.range(..=time.to_be_bytes()).next_back() {
+            Some(kv) => Some(kv?),
+            None => tree.first()?,
+        };
+        match found {
+            None => Ok(None),
+            Some((k, v)) => Ok(Some(decode_snapshot(&k, &v)?)),
+        }
+    }
+
+    /// Removes snapshots taken before `time`, returning how many were removed.
+    pub fn prune_snapshots(&mut self, time: Integer) -> Result<usize> {
+        let tree = self.db.open_tree("stats")?;
+        let mut removed = 0;
+        for kv in tree.range(..time.to_be_bytes()) {
+            let (k, _) = kv?;
+            tree.remove(k)?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+}
+
+/// Metadata of a chat fetched by `GetChat`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatInfo {
+    pub title: Option<String>,
+    pub username: Option<String>,
+    /// Unix time when the metadata was fetched.
+    pub time: Integer,
+}
+
+impl Cache {
+    // Chat metadata is keyed by big-endian chat id.
+    pub fn chat_info(&self, chat: i64) -> Result<Option<ChatInfo>> {
+        match self.db.open_tree("chats")?.get(chat.to_be_bytes())? {
+            None => Ok(None),
+            Some(v) => Ok(Some(decode(&v)?)),
+        }
+    }
+
+    pub fn set_chat_info(&mut self, chat: i64, info: &ChatInfo) -> Result<()> {
+        debug!("chat info set: {}, {:?}", chat, info);
+        self.db
+            .open_tree("chats")?
+            .insert(chat.to_be_bytes(), self.format.encode(info)?)?;
+        Ok(())
+    }
+}
+
+impl Cache {
+    /// Push a dead letter into queue, dropping the oldest ones if queue
+    /// holds more than `max` letters.
+    pub fn push_dead_letter(&mut self, d: &DeadLetter, max: usize) -> Result<()> {
+        let tree = self.db.open_tree("deadletter")?;
+        let key = self.db.generate_id()?;
+        debug!("dead letter push: {}, {:?}", key, d);
+        tree.insert(key.to_be_bytes(), self.format.encode(d)?)?;
+
+        while tree.len() > max {
+            match tree.pop_min()? {
+                Some((k, _)) => warn!("dead letter queue is full, drop {:?}", k),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn dead_letters(&self) -> Result<Vec<(u64, DeadLetter)>> {
+        let mut letters = Vec::new();
+        for kv in self.db.open_tree("deadletter")?.iter() {
+            let (k, v) = kv?;
+            let mut key = [0; 8];
+            key.copy_from_slice(&k);
+            letters.push((u64::from_be_bytes(key), decode(&v)?));
+        }
+        Ok(letters)
+    }
+
+    /// Count another failed attempt of the dead letter, letters dropped
+    /// meanwhile are left out.
+    pub fn fail_dead_letter(&mut self, key: u64, error: &str) -> Result<()> {
+        let tree = self.db.open_tree("deadletter")?;
+        update(&tree, key.to_be_bytes(), |v| {
+            let mut d: DeadLetter = match v {
+                Some(v) => decode(v)?,
+                None => return Ok(None),
+            };
+            d.attempts += 1;
+            d.error = error.to_string();
+            Ok(Some(self.format.encode(&d)?))
+        })?;
+        Ok(())
+    }
+
+    pub fn remove_dead_letter(&mut self, key: u64) -> Result<()> {
+        self.db.open_tree("deadletter")?.remove(key.to_be_bytes())?;
+        Ok(())
+    }
+}
+
+impl Cache {
+    // Prompts are keyed by big-endian id of the original message, holding
+    // chats and ids of all prompts asking about it.
+    pub fn push_prompt(&mut self, original: MessageId, prompt: (ChatId, MessageId)) -> Result<()> {
+        debug!("prompt push: {}, {:?}", original, prompt);
+        let tree = self.db.open_tree("prompts")?;
+        update(&tree, Integer::from(original).to_be_bytes(), |v| {
+            let mut prompts: Vec<(ChatId, MessageId)> = match v {
+                Some(v) => decode(v)?,
+                None => Vec::new(),
+            };
+            prompts.push(prompt);
+            Ok(Some(self.format.encode(&prompts)?))
+        })?;
+        Ok(())
+    }
+
+    pub fn prompts(&self, original: MessageId) -> Result<Vec<(ChatId, MessageId)>> {
+        match self
+            .db
+            .open_tree("prompts")?
+            .get(Integer::from(original).to_be_bytes())?
+        {
+            None => Ok(Vec::new()),
+            Some(v) => Ok(decode(&v)?),
+        }
+    }
+
+    /// Remove and return prompts of the original message.
+    pub fn take_prompts(&mut self, original: MessageId) -> Result<Vec<(ChatId, MessageId)>> {
+        match self
+            .db
+            .open_tree("prompts")?
+            .remove(Integer::from(original).to_be_bytes())?
+        {
+            None => Ok(Vec::new()),
+            Some(v) => Ok(decode(&v)?),
+        }
+    }
+
+    /// Returns a fresh id for a batch prompt.
+    pub fn batch_id(&self) -> Result<u64> {
+        Ok(self.db.generate_id()?)
+    }
+
+    // Batches are keyed by big-endian generated ids.
+    pub fn set_batch(&mut self, id: u64, b: &Batch) -> Result<()> {
+        debug!("batch set: {}, {:?}", id, b);
+        self.db
+            .open_tree("batches")?
+            .insert(id.to_be_bytes(), self.format.encode(b)?)?;
+        Ok(())
+    }
+
+    pub fn batch(&self, id: u64) -> Result<Option<Batch>> {
+        match self.db.open_tree("batches")?.get(id.to_be_bytes())? {
+            None => Ok(None),
+            Some(v) => Ok(Some(decode(&v)?)),
+        }
+    }
+
+    /// Remove the batch so that it can't be applied again.
+    pub fn take_batch(&mut self, id: u64) -> Result<Option<Batch>> {
+        match self.db.open_tree("batches")?.remove(id.to_be_bytes())? {
+            None => Ok(None),
+            Some(v) => Ok(Some(decode(&v)?)),
+        }
+    }
+
+    // Flags are keyed by big-endian unix time followed by a generated id, so
+    // that flags at the same second don't overwrite each other.
+    pub fn push_flag(&mut self, f: &Flag) -> Result<()> {
+        let mut key = f.time.to_be_bytes().to_vec();
+        key.extend_from_slice(&self.db.generate_id()?.to_be_bytes());
+        debug!("flag push: {:?}", f);
+        self.db.open_tree("flags")?.insert(key, self.format.encode(f)?)?;
+        Ok(())
+    }
+
+    /// Returns flags recorded in `[start, end)`.
+    pub fn flags_between(&self, start: Integer, end: Integer) -> Result<Vec<Flag>> {
+        let mut flags = Vec::new();
+        for kv in self
+            .db
+            .open_tree("flags")?
+            .range(start.to_be_bytes()..end.to_be_bytes())
+        {
+            let (_, v) = kv?;
+            flags.push(decode_flag(&v)?);
+        }
+        Ok(flags)
+    }
+
+    /// Returns open flags along with their ids, oldest first.
+    pub fn open_flags(&self) -> Result<Vec<(u64, Flag)>> {
+        let mut flags = Vec::new();
+        for kv in self.db.open_tree("flags")?.iter() {
+            let (k, v) = kv?;
+            let f = decode_flag(&v)?;
+            if f.resolved.is_none() {
+                flags.push((flag_id(&k), f));
+            }
+        }
+        Ok(flags)
+    }
+
+    /// Returns flags of the author along with their ids, oldest first.
+    pub fn author_flags(&self, author: UserId) -> Result<Vec<(u64, Flag)>> {
+        let mut flags = Vec::new();
+        for kv in self.db.open_tree("flags")?.iter() {
+            let (k, v) = kv?;
+            let f = decode_flag(&v)?;
+            if f.author == Some(author) {
+                flags.push((flag_id(&k), f));
+            }
+        }
+        Ok(flags)
+    }
+
+    /// Mark the flag as resolved at `time`, returns false if the flag is not
+    /// found or has been resolved.
+    pub fn resolve_flag(&mut self, id: u64, time: Integer) -> Result<bool> {
+        let tree = self.db.open_tree("flags")?;
+        for kv in tree.iter() {
+            let (k, _) = kv?;
+            if flag_id(&k) != id {
+                continue;
+            }
+            return self.resolve_flag_at(&tree, &k, time);
+        }
+        Ok(false)
+    }
+
+    /// Mark all open flags of the author as resolved at `time`, returns how
+    /// many flags were resolved.
+    pub fn resolve_author_flags(&mut self, author: UserId, time: Integer) -> Result<usize> {
+        let tree = self.db.open_tree("flags")?;
+        let mut resolved = 0;
+        for kv in tree.iter() {
+            let (k, v) = kv?;
+            let f = decode_flag(&v)?;
+            if f.resolved.is_some() || f.author != Some(author) {
+                continue;
+            }
+            if self.resolve_flag_at(&tree, &k, time)? {
+                resolved += 1;
+            }
+        }
+        Ok(resolved)
+    }
+
+    // Resolve the flag unless it's resolved or removed meanwhile.
+    fn resolve_flag_at(&self, tree: &sled::Tree, k: &[u8], time: Integer) -> Result<bool> {
+        update(tree, k, |v| {
+            let mut f = match v {
+                Some(v) => decode_flag(v)?,
+                None => return Ok(None),
+            };
+            if f.resolved.is_some() {
+                return Ok(None);
+            }
+            f.resolved = Some(time);
+            Ok(Some(self.format.encode(&f)?))
+        })
+    }
+
+    /// Removes flags resolved before `resolved_before` and open flags
+    /// recorded before `open_before`, returning how many were removed.
+    pub fn prune_flags(
+        &mut self,
+        resolved_before: Option<Integer>,
+        open_before: Option<Integer>,
+    ) -> Result<usize> {
+        let tree = self.db.open_tree("flags")?;
+        let mut removed = 0;
+        for kv in tree.iter() {
+            let (k, v) = kv?;
+            let f = decode_flag(&v)?;
+            let expired = match f.resolved {
+                Some(t) => resolved_before.is_some_and(|before| t < before),
+                None => open_before.is_some_and(|before| f.time < before),
+            };
+            if expired {
+                tree.remove(k)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Returns the scheduled time of the last posted daily summary.
+    pub fn last_summary(&self) -> Result<Option<Integer>> {
+        match self.db.open_tree("meta")?.get("last_summary")? {
+            None => Ok(None),
+            Some(v) => Ok(Some(decode(&v)?)),
+        }
+    }
+
+    pub fn set_last_summary(&mut self, time: Integer) -> Result<()> {
+        self.db
+            .open_tree("meta")?
+            .insert("last_summary", self.format.encode(&time)?)?;
+        Ok(())
+    }
+
+    /// Returns the chat and id of the pinned daily summary.
+    pub fn pinned_summary(&self) -> Result<Option<(ChatId, MessageId)>> {
+        match self.db.open_tree("meta")?.get("pinned_summary")? {
+            None => Ok(None),
+            Some(v) => Ok(Some(decode(&v)?)),
+        }
+    }
+
+    pub fn set_pinned_summary(&mut self, pinned: (ChatId, MessageId)) -> Result<()> {
+        self.db
+            .open_tree("meta")?
+            .insert("pinned_summary", self.format.encode(&pinned)?)?;
+        Ok(())
+    }
+
+    pub fn rules(&self) -> Result<Option<Rules>> {
+        match self.db.open_tree("meta")?.get("rules")? {
+            None => Ok(None),
+            Some(v) => Ok(Some(decode(&v)?)),
+        }
+    }
+
+    pub fn set_rules(&mut self, rules: &Rules) -> Result<()> {
+        self.db
+            .open_tree("meta")?
+            .insert("rules", self.format.encode(rules)?)?;
+        Ok(())
+    }
+
+    // Limiter states are keyed by names of the limiters.
+    pub fn set_limits<K: Serialize>(&mut self, name: &str, state: &[(K, Vec<Integer>)]) -> Result<()> {
+        self.db
+            .open_tree("limits")?
+            .insert(name, self.format.encode(&state)?)?;
+        Ok(())
+    }
+
+    pub fn limits<K: DeserializeOwned>(&self, name: &str) -> Result<Vec<(K, Vec<Integer>)>> {
+        match self.db.open_tree("limits")?.get(name)? {
+            None => Ok(Vec::new()),
+            Some(v) => Ok(decode(&v)?),
+        }
+    }
+
+    /// Returns a fresh id for an appeal.
+    pub fn appeal_id(&self) -> Result<u64> {
+        Ok(self.db.generate_id()?)
+    }
+
+    // Appeals are keyed by big-endian generated ids.
+    pub fn set_appeal(&mut self, id: u64, a: &Appeal) -> Result<()> {
+        debug!("appeal set: {}, {:?}", id, a);
+        self.db
+            .open_tree("appeals")?
+            .insert(id.to_be_bytes(), self.format.encode(a)?)?;
+        Ok(())
+    }
+
+    pub fn appeal(&self, id: u64) -> Result<Option<Appeal>> {
+        match self.db.open_tree("appeals")?.get(id.to_be_bytes())? {
+            None => Ok(None),
+            Some(v) => Ok(Some(decode(&v)?)),
+        }
+    }
+
+    /// Returns how many appeals were sent in `[start, end)`.
+    pub fn appeals_between(&self, start: Integer, end: Integer) -> Result<usize> {
+        let mut n = 0;
+        for kv in self.db.open_tree("appeals")?.iter() {
+            let (_, v) = kv?;
+            let a: Appeal = decode(&v)?;
+            if start <= a.time && a.time < end {
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+
+    // Alerts are keyed by big-endian ids of the messages they are about.
+    pub fn set_alert(&mut self, original: MessageId, a: &Alert) -> Result<()> {
+        self.db
+            .open_tree("alerts")?
+            .insert(Integer::from(original).to_be_bytes(), self.format.encode(a)?)?;
+        Ok(())
+    }
+
+    pub fn alert(&self, original: MessageId) -> Result<Option<Alert>> {
+        match self.db.open_tree("alerts")?.get(Integer::from(original).to_be_bytes())? {
+            None => Ok(None),
+            Some(v) => Ok(Some(decode(&v)?)),
+        }
+    }
+
+    // Text histories are keyed by big-endian message ids, holding times and
+    // texts oldest first.
+    /// Append the text to the message's history. The original text is
+    /// always kept, the oldest edit is dropped once there are `depth` texts.
+    /// Edits of messages without history are dropped since their original
+    /// text is unknown.
+    pub fn push_history(
+        &mut self,
+        id: MessageId,
+        time: Integer,
+        text: &str,
+        edit: bool,
+        depth: usize,
+    ) -> Result<()> {
+        let tree = self.db.open_tree("history")?;
+        let mut text = text.to_string();
+        if text.chars().count() > self.max_text_len {
+            text = text.chars().take(self.max_text_len).collect();
+            text.push_str(TRUNCATED);
+        }
+        update(&tree, Integer::from(id).to_be_bytes(), |v| {
+            let mut history: Vec<(Integer, String)> = match v {
+                Some(v) => decode(v)?,
+                None if edit => return Ok(None),
+                None => Vec::new(),
+            };
+            if history.last().is_some_and(|(_, last)| *last == text) {
+                return Ok(None);
+            }
+            history.push((time, text.clone()));
+            while history.len() > depth.max(2) {
+                history.remove(1);
+            }
+            Ok(Some(self.format.encode(&history)?))
+        })?;
+        Ok(())
+    }
+
+    pub fn history(&self, id: MessageId) -> Result<Vec<(Integer, String)>> {
+        match self.db.open_tree("history")?.get(Integer::from(id).to_be_bytes())? {
+            None => Ok(Vec::new()),
+            Some(v) => Ok(decode(&v)?),
+        }
+    }
+
+    /// Removes histories last changed before `time`, returning how many
+    /// were removed.
+    pub fn prune_history(&mut self, time: Integer) -> Result<usize> {
+        let tree = self.db.open_tree("history")?;
+        let mut removed = 0;
+        for kv in tree.iter() {
+            let (k, v) = kv?;
+            let history: Vec<(Integer, String)> = decode(&v)?;
+            if history.last().is_none_or(|(t, _)| *t < time) {
+                tree.remove(k)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    // Bans by the bot are keyed by big-endian user ids, holding the time of
+    // the ban.
+    pub fn push_ban(&mut self, user: UserId, time: Integer) -> Result<()> {
+        debug!("ban push: {}, {}", user, time);
+        self.db
+            .open_tree("bans")?
+            .insert(Integer::from(user).to_be_bytes(), self.format.encode(&time)?)?;
+        Ok(())
+    }
+
+    /// Returns how many users were banned in `[start, end)`.
+    pub fn bans_between(&self, start: Integer, end: Integer) -> Result<usize> {
+        let mut n = 0;
+        for kv in self.db.open_tree("bans")?.iter() {
+            let (_, v) = kv?;
+            let time: Integer = decode(&v)?;
+            if start <= time && time < end {
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+
+    pub fn is_banned(&self, user: UserId) -> Result<bool> {
+        Ok(self
+            .db
+            .open_tree("bans")?
+            .contains_key(Integer::from(user).to_be_bytes())?)
+    }
+
+    // Mutes are keyed by big-endian user ids.
+    pub fn set_mute(&mut self, user: UserId, mute: &Mute) -> Result<()> {
+        debug!("mute set: {}, {:?}", user, mute);
+        self.db
+            .open_tree("mutes")?
+            .insert(Integer::from(user).to_be_bytes(), self.format.encode(mute)?)?;
+        Ok(())
+    }
+
+    pub fn mute(&self, user: UserId) -> Result<Option<Mute>> {
+        match self.db.open_tree("mutes")?.get(Integer::from(user).to_be_bytes())? {
+            None => Ok(None),
+            Some(v) => Ok(Some(decode(&v)?)),
+        }
+    }
+
+    pub fn remove_mute(&mut self, user: UserId) -> Result<Option<Mute>> {
+        match self.db.open_tree("mutes")?.remove(Integer::from(user).to_be_bytes())? {
+            None => Ok(None),
+            Some(v) => Ok(Some(decode(&v)?)),
+        }
+    }
+
+    /// Returns mutes that should be lifted at the time.
+    pub fn due_mutes(&self, time: Integer) -> Result<Vec<(UserId, Mute)>> {
+        let mut mutes = Vec::new();
+        for kv in self.db.open_tree("mutes")?.iter() {
+            let (k, v) = kv?;
+            let mute: Mute = decode(&v)?;
+            if mute.until <= time {
+                let mut user = [0; 8];
+                user.copy_from_slice(&k);
+                mutes.push((UserId::new(Integer::from_be_bytes(user)), mute));
+            }
+        }
+        Ok(mutes)
+    }
+
+    /// Remove the ban record of the user, returns whether there was one.
+    pub fn remove_ban(&mut self, user: UserId) -> Result<bool> {
+        Ok(self
+            .db
+            .open_tree("bans")?
+            .remove(Integer::from(user).to_be_bytes())?
+            .is_some())
+    }
+}
+
+/// Replace the value at the key with what `f` makes of the current one,
+/// retrying on the new value if it's changed meanwhile, so that concurrent
+/// updates aren't lost. `f` returns `None` to leave the value as is.
+/// Returns whether the value is replaced.
+fn update<K, F>(tree: &sled::Tree, key: K, mut f: F) -> Result<bool>
+where
+    K: AsRef<[u8]>,
+    F: FnMut(Option<&[u8]>) -> Result<Option<Vec<u8>>>,
+{
+    loop {
+        let old = tree.get(&key)?;
+        let new = match f(old.as_deref())? {
+            Some(new) => new,
+            None => return Ok(false),
+        };
+        if tree.compare_and_swap(&key, old, Some(new))?.is_ok() {
+            return Ok(true);
+        }
+    }
+}
+
+// Messages cached before text hashes were kept.
+#[derive(Deserialize)]
+struct UnhashedMessage {
+    id: MessageId,
+    text: Option<String>,
+}
+
+fn decode_message(v: &[u8]) -> Result<CachedMessage> {
+    if let Ok(m) = decode(v) {
+        return Ok(m);
+    }
+    if let Ok(m) = decode::<UnhashedMessage>(v) {
+        return Ok(CachedMessage {
+            id: m.id,
+            text: m.text,
+            hash: None,
+        });
+    }
+    // Entries written before text caching only contain the message id.
+    Ok(CachedMessage {
+        id: MessageId::from(bincode::deserialize::<i64>(v)?),
+        text: None,
+        hash: None,
+    })
+}
+
+// Flags recorded before they could be resolved.
+#[derive(Deserialize)]
+struct LegacyFlag {
+    time: Integer,
+    reason: String,
+    admin: UserId,
+    author: Option<UserId>,
+    author_name: String,
+    suppressed: bool,
+}
+
+// Flags recorded before DMs to authors were tracked.
+#[derive(Deserialize)]
+struct UndeliveredFlag {
+    time: Integer,
+    reason: String,
+    admin: UserId,
+    author: Option<UserId>,
+    author_name: String,
+    suppressed: bool,
+    message: Option<MessageId>,
+    resolved: Option<Integer>,
+}
+
+fn decode_flag(v: &[u8]) -> Result<Flag> {
+    if let Ok(f) = decode(v) {
+        return Ok(f);
+    }
+    if let Ok(f) = decode::<UndeliveredFlag>(v) {
+        return Ok(Flag {
+            time: f.time,
+            reason: f.reason,
+            admin: f.admin,
+            author: f.author,
+            author_name: f.author_name,
+            suppressed: f.suppressed,
+            message: f.message,
+            resolved: f.resolved,
+            dm: None,
+        });
+    }
+    let f: LegacyFlag = decode(v)?;
+    Ok(Flag {
+        time: f.time,
+        reason: f.reason,
+        admin: f.admin,
+        author: f.author,
+        author_name: f.author_name,
+        suppressed: f.suppressed,
+        message: None,
+        resolved: None,
+        dm: None,
+    })
+}
+
+// Id of a flag is the generated id after its time in the key.
+fn flag_id(k: &[u8]) -> u64 {
+    let mut id = [0; 8];
+    id.copy_from_slice(&k[8..16]);
+    u64::from_be_bytes(id)
+}
+
+fn decode_snapshot(k: &[u8], v: &[u8]) -> Result<(Integer, Counters)> {
+    let mut time = [0; 8];
+    time.copy_from_slice(k);
+    Ok((Integer::from_be_bytes(time), decode(v)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_parallel_increments() {
+        let cache = Cache::temporary().unwrap();
+        let tree = cache.db.open_tree("counters").unwrap();
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let tree = tree.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        update(&tree, "n", |v| {
+                            let n = v.map_or(0, |v| bincode::deserialize::<u64>(v).unwrap());
+                            Ok(Some(bincode::serialize(&(n + 1))?))
+                        })
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        let n: u64 = bincode::deserialize(&tree.get("n").unwrap().unwrap()).unwrap();
+        assert_eq!(n, 800);
+    }
+
+    #[test]
+    fn get_messages_without_hash() {
+        let cache = Cache::temporary().unwrap();
+        let old = bincode::serialize(&(MessageId::from(1), Some("hi".to_string()))).unwrap();
+        cache.db.insert("10/alice", old).unwrap();
+        cache.db.insert("20/bob", bincode::serialize(&2i64).unwrap()).unwrap();
+
+        let m = cache.get_exact(10, "alice").unwrap().unwrap();
+        assert_eq!((m.id, m.text.as_deref(), m.hash), (MessageId::from(1), Some("hi"), None));
+        let m = cache.get_exact(20, "bob").unwrap().unwrap();
+        assert_eq!((m.id, m.text, m.hash), (MessageId::from(2), None, None));
+    }
+
+    #[test]
+    fn get_within_date_tolerance() {
+        let mut cache = Cache::temporary().unwrap();
+        let m = |id| CachedMessage {
+            id: MessageId::from(id),
+            text: None,
+            hash: None,
+        };
+        cache.set(100, "alice".to_string(), m(1)).unwrap();
+        cache.set(200, "bob".to_string(), m(2)).unwrap();
+
+        assert!(cache.get(101, "alice".to_string()).unwrap().is_none());
+
+        cache.date_tolerance(1);
+        for time in [99, 100, 101].iter() {
+            let got = cache.get(*time, "alice".to_string()).unwrap().unwrap();
+            assert_eq!(got.id, MessageId::from(1));
+        }
+        assert!(cache.get(102, "alice".to_string()).unwrap().is_none());
+        assert!(cache.get(201, "alice".to_string()).unwrap().is_none());
+    }
+
+    #[test]
+    fn get_across_formats() {
+        let mut cache = Cache::temporary().unwrap();
+        let m = |id| CachedMessage {
+            id: MessageId::from(id),
+            text: Some("hi".to_string()),
+            hash: Some(text_hash("hi")),
+        };
+        cache.format(Format::Bincode);
+        cache.set(1, "alice".to_string(), m(1)).unwrap();
+        cache.format(Format::Json);
+        cache.set(2, "bob".to_string(), m(2)).unwrap();
+
+        for (time, name, id) in [(1, "alice", 1), (2, "bob", 2)].iter() {
+            let got = cache.get(*time, name.to_string()).unwrap().unwrap();
+            assert_eq!((got.id, got.text.as_deref()), (MessageId::from(*id), Some("hi")));
+        }
+        cache.format(Format::Bincode);
+        assert!(cache.get(2, "bob".to_string()).unwrap().is_some());
+    }
+
+    #[test]
+    fn text_hash_is_stable() {
+        assert_eq!(text_hash(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(text_hash("a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn push_prompt_keeps_all() {
+        let mut cache = Cache::temporary().unwrap();
+        let original = MessageId::from(1);
+        for i in 0..3 {
+            cache
+                .push_prompt(original, (ChatId::from(-1), MessageId::from(i)))
+                .unwrap();
+        }
+
+        assert_eq!(cache.prompts(original).unwrap().len(), 3);
+        assert_eq!(cache.take_prompts(original).unwrap().len(), 3);
+        assert!(cache.prompts(original).unwrap().is_empty());
     }
 }
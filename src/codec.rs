@@ -0,0 +1,86 @@
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+// Encoded values start with this magic followed by a format byte. Values
+// written before formats were configurable have no tag and are bincode.
+const MAGIC: &[u8] = b"\xffOB";
+
+/// Encoding of values stored in cache.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Compact binary encoding.
+    #[default]
+    Bincode,
+    /// Human readable, useful for inspecting the db with external tools.
+    Json,
+}
+
+impl Format {
+    fn tag(self) -> u8 {
+        match self {
+            Format::Bincode => b'b',
+            Format::Json => b'j',
+        }
+    }
+
+    pub fn encode<T: Serialize + ?Sized>(self, v: &T) -> Result<Vec<u8>> {
+        let mut buf = MAGIC.to_vec();
+        buf.push(self.tag());
+        match self {
+            Format::Bincode => bincode::serialize_into(&mut buf, v)?,
+            Format::Json => serde_json::to_writer(&mut buf, v)?,
+        }
+        Ok(buf)
+    }
+}
+
+/// Decode a value in whatever format it has been encoded in.
+pub fn decode<T: DeserializeOwned>(v: &[u8]) -> Result<T> {
+    Ok(match v.strip_prefix(MAGIC).and_then(|v| v.split_first()) {
+        Some((b'b', v)) => bincode::deserialize(v)?,
+        Some((b'j', v)) => serde_json::from_slice(v)?,
+        _ => bincode::deserialize(v)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Value {
+        id: i64,
+        text: Option<String>,
+    }
+
+    fn value() -> Value {
+        Value {
+            id: -42,
+            text: Some("你好".to_string()),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for format in [Format::Bincode, Format::Json].iter() {
+            let encoded = format.encode(&value()).unwrap();
+            assert_eq!(encoded[MAGIC.len()], format.tag());
+            assert_eq!(decode::<Value>(&encoded).unwrap(), value());
+        }
+    }
+
+    #[test]
+    fn decode_untagged_bincode() {
+        let encoded = bincode::serialize(&value()).unwrap();
+        assert_eq!(decode::<Value>(&encoded).unwrap(), value());
+    }
+
+    #[test]
+    fn decode_json_as_readable() {
+        let encoded = Format::Json.encode(&value()).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&encoded[MAGIC.len() + 1..]).unwrap();
+        assert_eq!(json["text"], "你好");
+    }
+}
@@ -0,0 +1,334 @@
+use anyhow::{anyhow, Result};
+use telegram_bot::{Integer, UserId};
+
+/// Kind of a command argument.
+// Not every kind is taken by current commands yet.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// Duration like `30s`, `5m`, `2h` or `1d`, plain numbers are seconds.
+    Duration,
+    /// Non-negative integer.
+    Count,
+    /// Telegram user id.
+    UserId,
+    /// A single word.
+    Word,
+    /// Everything till the end of the command, must be the last argument.
+    Text,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Param {
+    pub name: &'static str,
+    pub kind: Kind,
+    pub optional: bool,
+}
+
+/// Argument shape of a command.
+///
+/// Optional params must come after all required params.
+#[derive(Debug, Clone, Copy)]
+pub struct Spec {
+    pub name: &'static str,
+    pub params: &'static [Param],
+    /// Read-only commands never need a confirmation.
+    pub read_only: bool,
+    /// The first param is a user id, which is taken from the replied
+    /// message instead if it's left out.
+    pub reply_target: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// Duration in seconds.
+    Duration(Integer),
+    Count(u64),
+    UserId(UserId),
+    Text(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Command {
+    pub name: String,
+    /// Bot username in `/command@botname`.
+    pub bot: Option<String>,
+    /// Text after command name.
+    pub rest: String,
+}
+
+const fn required(name: &'static str, kind: Kind) -> Param {
+    Param {
+        name,
+        kind,
+        optional: false,
+    }
+}
+
+const fn optional(name: &'static str, kind: Kind) -> Param {
+    Param {
+        name,
+        kind,
+        optional: true,
+    }
+}
+
+/// All commands that the bot accepts.
+pub const COMMANDS: &[Spec] = &[
+    Spec {
+        name: "stats",
+        params: &[optional("小时数", Kind::Count)],
+        read_only: true,
+        reply_target: false,
+    },
+    Spec {
+        name: "admins",
+        params: &[],
+        read_only: true,
+        reply_target: false,
+    },
+    Spec {
+        name: "menu",
+        params: &[],
+        read_only: true,
+        reply_target: false,
+    },
+    Spec {
+        name: "alerts",
+        params: &[],
+        read_only: true,
+        reply_target: false,
+    },
+    Spec {
+        name: "resolve",
+        params: &[required("编号", Kind::Count)],
+        read_only: false,
+        reply_target: false,
+    },
+    Spec {
+        name: "forgive",
+        params: &[required("用户 ID", Kind::UserId)],
+        read_only: false,
+        reply_target: true,
+    },
+    Spec {
+        name: "whois",
+        params: &[required("用户 ID", Kind::UserId)],
+        read_only: true,
+        reply_target: true,
+    },
+    Spec {
+        name: "mute",
+        params: &[required("用户 ID", Kind::UserId), required("时长", Kind::Duration)],
+        read_only: false,
+        reply_target: true,
+    },
+    Spec {
+        name: "syncrules",
+        params: &[],
+        read_only: false,
+        reply_target: false,
+    },
+    Spec {
+        name: "testfilter",
+        params: &[required("文本", Kind::Text)],
+        read_only: true,
+        reply_target: false,
+    },
+    Spec {
+        name: "history",
+        params: &[required("消息 ID", Kind::Count)],
+        read_only: true,
+        reply_target: false,
+    },
+    Spec {
+        name: "loglevel",
+        params: &[required("理由", Kind::Word), optional("级别", Kind::Word)],
+        read_only: false,
+        reply_target: false,
+    },
+    Spec {
+        name: "confirm",
+        params: &[required("确认码", Kind::Text)],
+        read_only: true,
+        reply_target: false,
+    },
+    Spec {
+        name: "deadletter",
+        params: &[],
+        read_only: true,
+        reply_target: false,
+    },
+    Spec {
+        name: "retry_deadletter",
+        params: &[],
+        read_only: false,
+        reply_target: false,
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Spec> {
+    COMMANDS.iter().find(|s| s.name == name)
+}
+
+/// Split text starts with `/` into command name, bot username and the rest.
+pub fn split(text: &str) -> Option<Command> {
+    let text = text.trim_start().strip_prefix('/')?;
+    let (head, rest) = match text.find(char::is_whitespace) {
+        Some(idx) => (&text[..idx], text[idx..].trim()),
+        None => (text, ""),
+    };
+    if head.is_empty() {
+        return None;
+    }
+
+    let (name, bot) = match head.find('@') {
+        Some(idx) => (&head[..idx], Some(head[idx + 1..].to_string())),
+        None => (head, None),
+    };
+
+    Some(Command {
+        name: name.to_lowercase(),
+        bot,
+        rest: rest.to_string(),
+    })
+}
+
+impl Spec {
+    pub fn usage(&self) -> String {
+        let mut usage = format!("/{}", self.name);
+        for p in self.params {
+            if p.optional {
+                usage.push_str(&format!(" [{}]", p.name));
+            } else {
+                usage.push_str(&format!(" <{}>", p.name));
+            }
+        }
+        usage
+    }
+
+    /// Parse arguments according to params, missing optional arguments are
+    /// not included in the returned values.
+    pub fn parse(&self, rest: &str) -> Result<Vec<Value>> {
+        let mut values = Vec::with_capacity(self.params.len());
+        let mut rest = rest.trim();
+
+        for p in self.params {
+            if rest.is_empty() {
+                if p.optional {
+                    break;
+                }
+                return Err(anyhow!("缺少参数 <{}>", p.name));
+            }
+
+            if p.kind == Kind::Text {
+                values.push(Value::Text(rest.to_string()));
+                rest = "";
+                break;
+            }
+
+            let (token, remain) = match rest.find(char::is_whitespace) {
+                Some(idx) => (&rest[..idx], rest[idx..].trim_start()),
+                None => (rest, ""),
+            };
+            values.push(parse_value(p, token)?);
+            rest = remain;
+        }
+
+        if !rest.is_empty() {
+            return Err(anyhow!("多余的参数：{}", rest));
+        }
+        Ok(values)
+    }
+}
+
+fn parse_value(p: &Param, token: &str) -> Result<Value> {
+    match p.kind {
+        Kind::Duration => parse_duration(token)
+            .map(Value::Duration)
+            .ok_or_else(|| anyhow!("参数 <{}> 不是有效的时长：{}", p.name, token)),
+        Kind::Count => token
+            .parse()
+            .map(Value::Count)
+            .map_err(|_| anyhow!("参数 <{}> 不是有效的数量：{}", p.name, token)),
+        Kind::UserId => token
+            .parse::<Integer>()
+            .map(|v| Value::UserId(UserId::from(v)))
+            .map_err(|_| anyhow!("参数 <{}> 不是有效的用户 ID：{}", p.name, token)),
+        Kind::Word | Kind::Text => Ok(Value::Text(token.to_string())),
+    }
+}
+
+fn parse_duration(s: &str) -> Option<Integer> {
+    let (num, unit) = match s.char_indices().last()? {
+        (idx, c) if c.is_ascii_alphabetic() => (&s[..idx], c.to_ascii_lowercase()),
+        _ => (s, 's'),
+    };
+    let num: Integer = num.parse().ok().filter(|v| *v >= 0)?;
+
+    let scale = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86400,
+        _ => return None,
+    };
+    num.checked_mul(scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_command() {
+        let cmd = split("/Mute@onobot  123 5m ").unwrap();
+        assert_eq!(cmd.name, "mute");
+        assert_eq!(cmd.bot.as_deref(), Some("onobot"));
+        assert_eq!(cmd.rest, "123 5m");
+
+        let cmd = split("/stats").unwrap();
+        assert_eq!(cmd.name, "stats");
+        assert_eq!(cmd.bot, None);
+        assert_eq!(cmd.rest, "");
+
+        assert!(split("stats").is_none());
+        assert!(split("/ stats").is_none());
+    }
+
+    #[test]
+    fn parse_arguments() {
+        let mute = find("mute").unwrap();
+        assert_eq!(
+            mute.parse("123 2h").unwrap(),
+            vec![Value::UserId(UserId::from(123)), Value::Duration(7200)]
+        );
+        assert!(mute.parse("123").is_err());
+        assert!(mute.parse("abc 2h").is_err());
+        assert!(mute.parse("123 2h 3").is_err());
+
+        let stats = find("stats").unwrap();
+        assert_eq!(stats.parse("").unwrap(), vec![]);
+        assert_eq!(stats.parse("24").unwrap(), vec![Value::Count(24)]);
+
+        let filter = find("testfilter").unwrap();
+        assert_eq!(
+            filter.parse(" buy  now ").unwrap(),
+            vec![Value::Text("buy  now".to_string())]
+        );
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("30"), Some(30));
+        assert_eq!(parse_duration("30s"), Some(30));
+        assert_eq!(parse_duration("5M"), Some(300));
+        assert_eq!(parse_duration("1d"), Some(86400));
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("-5m"), None);
+        assert_eq!(parse_duration("5w"), None);
+        assert_eq!(parse_duration("1.5h"), None);
+        assert_eq!(parse_duration("9223372036854775807d"), None);
+    }
+}
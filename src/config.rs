@@ -1,13 +1,1261 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+
 use serde::{Deserialize, Serialize};
 
+use super::codec::Format;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     pub token: String,
     pub db: String,
+    /// Name that tells this instance apart from others, defaults to the
+    /// hostname.
+    #[serde(default)]
+    pub instance_name: Option<String>,
 
+    /// Group where admins get prompts, audits and notifications, and run
+    /// commands.
     pub admin_group: i64,
+    /// Group the bot moderates: messages are cached and alerts are posted
+    /// here.
     pub main_group: i64,
+    /// Groups whose admins are allowed to use the bot, main group by
+    /// default.
+    #[serde(default)]
+    pub admin_source: AdminSource,
+    /// Users allowed to use the bot besides admins of `admin_source`.
+    #[serde(default)]
+    pub extra_admins: Vec<i64>,
+    /// Admins of `admin_source` not allowed to use the bot, like other bots.
+    /// Exclusions win over `extra_admins`.
+    #[serde(default)]
+    pub excluded_admins: Vec<i64>,
+    /// Users that are always admins, even if excluded, so that the bot stays
+    /// usable if admins of main group can't be fetched as expected.
+    #[serde(default)]
+    pub bootstrap_admins: Vec<i64>,
 
     pub offtopic_group: String,
     pub meta_group: String,
+    /// Labels of the buttons under alerts.
+    #[serde(default)]
+    pub buttons: Buttons,
+    /// Reply to users starting an appeal in private chat, see `Appeal::Dm`.
+    #[serde(default = "default_appeal_text")]
+    pub appeal_text: String,
+    /// Optional chat id of meta group to track appeals in. Once set, appeal
+    /// buttons linking to `meta_group` start an appeal in private chat
+    /// instead, which is posted to this chat with buttons for admins to
+    /// mark its status, and the status is shown under the appealed alert.
+    #[serde(default)]
+    pub appeal_queue: Option<i64>,
+    /// Optional channel that receives reasons targeting `log`.
+    #[serde(default)]
+    pub log_channel: Option<i64>,
+
+    /// Reasons that admins can choose from, defaults to off-topic only.
+    #[serde(default = "default_reasons")]
+    pub reasons: Vec<Reason>,
+
+    /// Interval in seconds between stats snapshots.
+    #[serde(default = "default_stats_interval")]
+    pub stats_interval: u64,
+    /// Days to keep stats snapshots, 0 means keep forever.
+    #[serde(default = "default_stats_retention")]
+    pub stats_retention: u64,
+    /// Days to keep flags after they are resolved, 0 means keep forever.
+    #[serde(default = "default_resolved_retention")]
+    pub resolved_retention: u64,
+    /// Days to keep flags that are never resolved, 0 means keep them until
+    /// they are resolved.
+    #[serde(default)]
+    pub open_retention: u64,
+
+    /// How users are referenced in admin group notifications.
+    #[serde(default)]
+    pub mention_style: MentionStyle,
+    /// Which labels of reasons are shown on buttons, see `Reason::labels`.
+    #[serde(default)]
+    pub label_style: LabelStyle,
+    /// Send admin group notifications with sound, silent by default.
+    #[serde(default)]
+    pub audit_notify: bool,
+    /// Quote text of flagged messages in admin group audits, if cached.
+    #[serde(default = "default_audit_quote")]
+    pub audit_quote: bool,
+    /// Whether open flags count toward the standing of their authors shown
+    /// by `/whois`, so that benign nudges can be told apart from offenses.
+    #[serde(default = "default_counts_toward_escalation")]
+    pub counts_toward_escalation: bool,
+    /// Seconds that audits are collected into a single digest, so busy
+    /// periods don't flood admin group, 0 sends each audit at once.
+    #[serde(default)]
+    pub audit_window: u64,
+    /// Max audits in a digest, full digests are sent at once.
+    #[serde(default = "default_audit_max")]
+    pub audit_max: usize,
+
+    /// Window in seconds of alert cooldown, 0 disables cooldown.
+    #[serde(default)]
+    pub alert_cooldown: u64,
+    /// Alerts allowed in the cooldown window.
+    #[serde(default = "default_alert_cooldown_limit")]
+    pub alert_cooldown_limit: usize,
+    /// What the cooldown is counted by.
+    #[serde(default)]
+    pub cooldown_scope: CooldownScope,
+    /// Toast shown when an alert is cooling down, `{secs}` is replaced with
+    /// the seconds until the next alert is allowed.
+    #[serde(default = "default_cooldown_text")]
+    pub cooldown_text: String,
+    /// Toast shown when flagging a message from a user who hides their
+    /// account in forwards, the alert is still posted but can't mention or
+    /// track the user. Empty disables it.
+    #[serde(default = "default_hidden_user_text")]
+    pub hidden_user_text: String,
+
+    /// Interval in seconds between retries of failed actions.
+    #[serde(default = "default_deadletter_interval")]
+    pub deadletter_interval: u64,
+    /// Max failed actions kept for retry, oldest ones are dropped first.
+    #[serde(default = "default_deadletter_max")]
+    pub deadletter_max: usize,
+    /// Seconds after which failed actions are given up.
+    #[serde(default = "default_deadletter_max_age")]
+    pub deadletter_max_age: u64,
+
+    /// Alerts to groups with more members than this need to be confirmed,
+    /// 0 disables confirmation.
+    #[serde(default)]
+    pub confirm_member_threshold: i64,
+    /// Seconds that a confirmation button or code stays valid.
+    #[serde(default = "default_confirm_timeout")]
+    pub confirm_timeout: u64,
+
+    /// Seconds after reminding a user that later flags of their messages
+    /// are only recorded, 0 disables it.
+    #[serde(default)]
+    pub user_reminder_cooldown: u64,
+
+    /// Seconds between on-demand checks of whether a user not known as an
+    /// admin has just been promoted.
+    #[serde(default = "default_admin_check_interval")]
+    pub admin_check_interval: u64,
+    /// Whether admins pressing buttons are checked again, in case they have
+    /// been demoted since the prompt was sent.
+    #[serde(default)]
+    pub callback_admin_check: CallbackAdminCheck,
+    /// Window in seconds limiting presses of the same kind of button by the
+    /// same user, 0 disables the limit. Limits are kept in memory only.
+    #[serde(default = "default_callback_window")]
+    pub callback_window: u64,
+    /// Presses allowed in the window for users not known as admins.
+    #[serde(default = "default_callback_limit")]
+    pub callback_limit: usize,
+    /// Presses allowed in the window for admins, who press buttons in
+    /// batches while cleaning up.
+    #[serde(default = "default_callback_admin_limit")]
+    pub callback_admin_limit: usize,
+
+    /// Seconds that forwards from the same admin are collected into a
+    /// single prompt, 0 disables batching.
+    #[serde(default)]
+    pub batch_window: u64,
+    /// Max forwards in a batch prompt, full batches are prompted at once.
+    #[serde(default = "default_batch_max")]
+    pub batch_max: usize,
+
+    /// Strip invisible and control characters from message text before
+    /// matching and quoting it, disable to keep raw text.
+    #[serde(default = "default_normalize_text")]
+    pub normalize_text: bool,
+
+    /// Encoding of button callback data, either `json` or `compact`.
+    ///
+    /// Telegram limits callback data to 64 bytes, `compact` leaves room for
+    /// long reason ids. Buttons sent in either format keep working.
+    #[serde(default)]
+    pub callback_format: CallbackFormat,
+
+    /// Ignore commands from users banned by the bot, in case they are still
+    /// able to send messages in a chat. This covers starting appeals and
+    /// `/ot` as well.
+    #[serde(default)]
+    pub ignore_banned_commands: bool,
+
+    /// Persist cooldowns of alerts, reminders and stickers in cache, so that
+    /// they aren't reset by restarts.
+    #[serde(default)]
+    pub persist_limits: bool,
+
+    /// What to do if an admin forwards their own message.
+    #[serde(default)]
+    pub self_flag: SelfFlag,
+
+    /// Channel whose posts are accepted as commands, channel posts are
+    /// ignored if unset.
+    ///
+    /// Channel posts carry no sender, so anyone able to post in the channel,
+    /// that is every channel admin, is trusted as a bot admin.
+    #[serde(default)]
+    pub command_channel: Option<i64>,
+    /// Commands that have to be confirmed with `/confirm <code>` when sent
+    /// in private chats, `forgive` and `retry_deadletter` by default.
+    /// Read-only commands can't be listed.
+    #[serde(default = "default_confirm_commands")]
+    pub confirm_commands: Vec<String>,
+    /// What to do if a message is edited into a command.
+    #[serde(default)]
+    pub edited_commands: EditedCommands,
+    /// Seconds after posting in which edits of main group messages are
+    /// checked against filters and spam score again, catching messages
+    /// edited into spam. 0 disables it.
+    #[serde(default)]
+    pub edit_recheck_window: u64,
+    /// What to do with messages in groups that mention the bot without a
+    /// command, ignored by default to keep busy groups quiet.
+    #[serde(default)]
+    pub mention_action: MentionAction,
+    /// Reply to mentions of the bot when `mention_action` is `reply`, also
+    /// replied to non-admins asking for help.
+    #[serde(default = "default_mention_text")]
+    pub mention_text: String,
+
+    /// Seconds that forward date may differ from the original message date.
+    #[serde(default = "default_forward_date_tolerance")]
+    pub forward_date_tolerance: u64,
+
+    /// Run the self-test on every start and report it to admin group, the
+    /// bot keeps running if it fails. See also `--selftest`.
+    #[serde(default)]
+    pub selftest: bool,
+    /// Announce the bot going online and offline, disabled if unset.
+    #[serde(default)]
+    pub announce: Option<Announce>,
+
+    /// Hooks fired after actions.
+    ///
+    /// Hooks run with the privileges of the bot and receive ids and names
+    /// of admins and users, only configure programs and urls you trust.
+    #[serde(default)]
+    pub hooks: Vec<Hook>,
+
+    /// Chat that receives critical errors like cache open failures.
+    #[serde(default)]
+    pub oncall_chat: Option<i64>,
+    /// Seconds between pages of the same error category.
+    #[serde(default = "default_oncall_interval")]
+    pub oncall_interval: u64,
+
+    /// Stickers and animations a user may post in main group within
+    /// `sticker_window` before being alerted automatically, 0 disables it.
+    /// Admins are exempted.
+    #[serde(default)]
+    pub sticker_limit: usize,
+    /// Window in seconds that stickers are counted in.
+    #[serde(default = "default_sticker_window")]
+    pub sticker_window: u64,
+    /// Id of the reason whose alert is posted when `sticker_limit` is
+    /// exceeded.
+    #[serde(default)]
+    pub sticker_reason: Option<String>,
+
+    /// Id of the reason applied at once when admins reply `/ot` to a
+    /// message in main group. If unset, the message is forwarded to the
+    /// admin privately with the reasons keyboard.
+    #[serde(default)]
+    pub default_reason: Option<String>,
+
+    /// Keywords whose messages in main group are alerted automatically,
+    /// test them with `/testfilter` before enabling fuzzy matching.
+    #[serde(default)]
+    pub filters: Vec<Filter>,
+
+    /// Distinct users posting the same text in main group within
+    /// `spam_wave_window` for admins to be notified of a spam wave, 0
+    /// disables it.
+    #[serde(default)]
+    pub spam_wave_users: usize,
+    /// Window in seconds that the same text is counted in.
+    #[serde(default = "default_spam_wave_window")]
+    pub spam_wave_window: u64,
+    /// Texts shorter than this many chars are ignored since short replies
+    /// are often the same.
+    #[serde(default = "default_spam_wave_min_len")]
+    pub spam_wave_min_len: usize,
+
+    /// Score messages in main group by weighted signals and act on high
+    /// scores, disabled if unset.
+    #[serde(default)]
+    pub spam_score: Option<SpamScore>,
+
+    /// Act on main group messages written in scripts the group doesn't
+    /// allow, disabled if unset since short or mixed messages are easily
+    /// misjudged. Scripts are checked instead of languages as no language
+    /// detection crate is vendored, see `script::detect`.
+    #[serde(default)]
+    pub scripts: Option<Scripts>,
+
+    /// Enter attack mode on frequent spam waves, disabled if unset.
+    #[serde(default)]
+    pub attack: Option<Attack>,
+    /// Screen requests to join main group, disabled if unset. Requests are
+    /// left to telegram clients of admins then.
+    #[serde(default)]
+    pub join_requests: Option<JoinRequests>,
+
+    /// Offset in minutes from UTC of the timezone that times of day are in.
+    #[serde(default)]
+    pub utc_offset: i32,
+    /// Timezones of chats overriding `utc_offset`, chats not listed here
+    /// fall back to it. The daily summary is scheduled in the timezone of
+    /// the chat it's posted to.
+    #[serde(default)]
+    pub timezones: Vec<ChatTimezone>,
+    /// Time of day in `HH:MM` to post the daily summary of flags, unset
+    /// disables the summary.
+    #[serde(default)]
+    pub summary_time: Option<String>,
+    /// Where the daily summary is posted.
+    #[serde(default)]
+    pub summary_target: SummaryTarget,
+    /// Don't post the daily summary if nothing has been flagged, banned or
+    /// appealed that day.
+    #[serde(default)]
+    pub summary_skip_empty: bool,
+    /// Pin the daily summary in its chat, unpinning the previous one. The
+    /// bot needs permission to pin messages there.
+    #[serde(default)]
+    pub summary_pin: bool,
+
+    /// What to do if the cache at `db` can't be opened.
+    #[serde(default)]
+    pub cache_fallback: CacheFallback,
+    /// Days to keep cached messages, 0 means keep forever. Messages older
+    /// than this can't be flagged.
+    #[serde(default)]
+    pub cache_retention: u64,
+    /// Cached messages scanned at once while pruning expired ones, passes
+    /// over large caches are spread over time so that updates are handled
+    /// in between.
+    #[serde(default = "default_prune_batch")]
+    pub prune_batch: usize,
+    /// Milliseconds between batches of pruning.
+    #[serde(default = "default_prune_pause")]
+    pub prune_pause: u64,
+    /// Encoding of values written into cache, either `bincode` or `json`.
+    #[serde(default)]
+    pub cache_format: Format,
+    /// What to do at startup with cache entries that older versions wrote
+    /// without fields added since. `upgrade` rewrites them with defaults
+    /// for the missing fields, `discard` removes them for a clean slate as
+    /// if they were never cached.
+    #[serde(default)]
+    pub cache_migration: CacheMigration,
+    /// Text messages shorter than this many chars are not cached since they
+    /// are rarely flagged, 0 caches all of them.
+    #[serde(default)]
+    pub min_cached_len: usize,
+    /// Kinds of messages that are not cached.
+    #[serde(default)]
+    pub uncached_kinds: Vec<ContentKind>,
+    /// Cache text of main group messages along with their ids.
+    #[serde(default)]
+    pub cache_text: bool,
+    /// Texts kept in the history of each main group message including the
+    /// original one, shown by `/history`. 0 disables it, and it's only kept
+    /// with `cache_text`.
+    #[serde(default)]
+    pub history_depth: usize,
+    /// Days to keep histories after the last edit, 0 means keep forever.
+    #[serde(default)]
+    pub history_retention: u64,
+    /// Cache the caption of an album as text of all its items, clients put
+    /// the caption on any one of them.
+    #[serde(default = "default_album_caption")]
+    pub album_caption: bool,
+    /// Max chars of cached text, longer text is truncated with a marker so
+    /// that quotes of very long messages are incomplete.
+    #[serde(default = "default_max_cached_text_len")]
+    pub max_cached_text_len: usize,
+    /// What to do if another instance is polling with the same token.
+    ///
+    /// Instances sharing the same `db` are already prevented by the lock
+    /// sled holds on it.
+    #[serde(default)]
+    pub on_conflict: OnConflict,
+    /// Max seconds to wait before fetching updates again after failures,
+    /// the wait doubles on every consecutive failure.
+    #[serde(default = "default_fetch_backoff_max")]
+    pub fetch_backoff_max: u64,
+    /// Seconds that a single long poll waits for updates, from 1 to 50.
+    /// Shorter polls notice a dropped connection sooner but make more
+    /// requests while idle, longer polls suit metered connections.
+    #[serde(default = "default_poll_timeout")]
+    pub poll_timeout: u64,
+    /// Seconds after which messages left from downtime are discarded at
+    /// startup instead of acted on, 0 processes all of them. Commands and
+    /// callbacks are always processed.
+    #[serde(default)]
+    pub max_update_age: u64,
+    /// Seconds that shutdown waits for the update being handled before
+    /// interrupting it, alerts cut off are retried from dead letters after
+    /// restart. Final messages like the offline announcement get the same
+    /// time.
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    /// Share of updates from 0 to 1 logged in full at debug level, the rest
+    /// are logged with only their id and kind at trace level. Updates are
+    /// picked by their id, so the same updates are sampled in every run.
+    /// 0 logs all updates in full at debug level.
+    #[serde(default)]
+    pub update_sample: f64,
+    /// Keep text and names of users out of logged updates, only ids, kinds
+    /// and lengths are logged.
+    #[serde(default)]
+    pub privacy_mode: bool,
+
+    /// Average API calls per minute shared by all features, calls over it
+    /// are delayed. 0 disables the budget.
+    #[serde(default)]
+    pub api_rate: u64,
+    /// API calls allowed at once before calls are delayed.
+    #[serde(default = "default_api_burst")]
+    pub api_burst: u64,
+}
+
+impl Config {
+    pub fn instance_name(&self) -> String {
+        if let Some(name) = &self.instance_name {
+            return name.clone();
+        }
+        fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|s| s.trim().to_string())
+            .ok()
+            .or_else(|| env::var("HOSTNAME").ok())
+            .filter(|s| !s.is_empty())
+            .unwrap_or_else(|| "onobot".to_string())
+    }
+
+    /// Groups that admins are fetched from according to `admin_source`.
+    pub fn admin_groups(&self) -> Vec<i64> {
+        match self.admin_source {
+            AdminSource::Main => vec![self.main_group],
+            AdminSource::Admin => vec![self.admin_group],
+            AdminSource::Both => vec![self.main_group, self.admin_group],
+        }
+    }
+
+    pub fn reason(&self, id: &str) -> Option<&Reason> {
+        self.reasons.iter().find(|r| r.id == id)
+    }
+
+    /// Label of the reason's button for an admin speaking `lang`.
+    pub fn button_label(&self, reason: &Reason, lang: Option<&str>) -> String {
+        match self.label_style {
+            LabelStyle::Primary => reason.label.clone(),
+            LabelStyle::Both => {
+                let mut label = reason.label.clone();
+                for other in reason.labels.values() {
+                    label.push_str(" / ");
+                    label.push_str(other);
+                }
+                label
+            }
+            LabelStyle::Admin => {
+                // `zh-hans` falls back to `zh`.
+                let lang = lang.unwrap_or_default();
+                reason
+                    .labels
+                    .get(lang)
+                    .or_else(|| lang.split('-').next().and_then(|l| reason.labels.get(l)))
+                    .unwrap_or(&reason.label)
+                    .clone()
+            }
+        }
+    }
+
+    /// Offset in minutes from UTC of the chat's timezone.
+    pub fn utc_offset(&self, chat: i64) -> i32 {
+        self.timezones
+            .iter()
+            .find(|t| t.chat == chat)
+            .map_or(self.utc_offset, |t| t.utc_offset)
+    }
+
+    /// Whether audits of the reason quote the flagged message.
+    pub fn audit_quote(&self, reason: &Reason) -> bool {
+        reason.audit_quote.unwrap_or(self.audit_quote)
+    }
+
+    /// Whether flags of the reason count toward the standing of authors.
+    pub fn counts_toward_escalation(&self, reason: &Reason) -> bool {
+        reason
+            .counts_toward_escalation
+            .unwrap_or(self.counts_toward_escalation)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Reason {
+    /// Short identifier embedded in callback data, keep it short since
+    /// telegram limits callback data to 64 bytes.
+    pub id: String,
+    /// Label of the button shown to admins.
+    pub label: String,
+    /// Labels of the button in other languages, keyed by language codes
+    /// like `en`, shown according to `label_style`.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Alert text posted when this reason is chosen.
+    pub text: String,
+    /// Where the alert is posted.
+    #[serde(default)]
+    pub target: Target,
+    /// Where the appeal button under the alert leads.
+    #[serde(default)]
+    pub appeal: Appeal,
+    /// Link of the appeal button if `appeal` is `url`.
+    #[serde(default)]
+    pub appeal_url: Option<String>,
+    /// Ask for a confirmation before posting the alert.
+    #[serde(default)]
+    pub requires_confirm: bool,
+    /// Kinds of messages this reason is offered for, empty means all kinds.
+    #[serde(default)]
+    pub kinds: Vec<ContentKind>,
+    /// Overrides `audit_quote` for this reason.
+    ///
+    /// Audits only link to messages flagged for reasons like NSFW, so that
+    /// the content isn't repeated to everyone in admin group, and isn't
+    /// kept in its history after the original is deleted.
+    #[serde(default)]
+    pub audit_quote: Option<bool>,
+    /// Optional explanation sent to the author in private chat along with
+    /// the alert, skipped silently for authors who haven't started the bot.
+    #[serde(default)]
+    pub dm_template: Option<String>,
+    /// Overrides `counts_toward_escalation` for this reason.
+    #[serde(default)]
+    pub counts_toward_escalation: Option<bool>,
+    /// Overrides the log level of handling this reason. Details are logged
+    /// at debug level, `debug` and `trace` show them even if debug logs of
+    /// everything else are off, lower levels hide them. Follows the global
+    /// level if unset. Changed at runtime with `/loglevel`.
+    #[serde(default)]
+    pub log_level: Option<LogLevel>,
+}
+
+/// Log levels ordered the same as `log::LevelFilter`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    const ALL: [LogLevel; 6] = [
+        LogLevel::Off,
+        LogLevel::Error,
+        LogLevel::Warn,
+        LogLevel::Info,
+        LogLevel::Debug,
+        LogLevel::Trace,
+    ];
+
+    pub fn parse(name: &str) -> Option<LogLevel> {
+        LogLevel::ALL.iter().copied().find(|level| level.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Off => "off",
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+}
+
+impl From<LogLevel> for log::LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => log::LevelFilter::Off,
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentKind {
+    Text,
+    Sticker,
+    /// GIFs, which are sent as mp4 or gif documents.
+    Animation,
+    /// Photos, videos and other documents.
+    Media,
+    Other,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    /// Reply to the original message in main group.
+    #[default]
+    Main,
+    /// Post to admin group with a link to the original message.
+    Admin,
+    /// Post to `log_channel` with a link to the original message.
+    Log,
+    /// Don't post anything.
+    None,
+}
+
+/// A hook receives the event as JSON, on stdin for commands and as POST
+/// body for urls.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hook {
+    /// Events that fire this hook.
+    pub events: Vec<Event>,
+    /// Program and its arguments, run without a shell and with a cleared
+    /// environment.
+    #[serde(default)]
+    pub command: Vec<String>,
+    /// URL that the event is posted to.
+    #[serde(default)]
+    pub url: Option<String>,
+    /// Seconds before the hook is killed.
+    #[serde(default = "default_hook_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    /// An admin flagged a message and the alert was posted.
+    Alert,
+    /// An admin flagged a message but the alert was skipped.
+    Suppressed,
+    /// A user was alerted automatically, like for too many stickers.
+    AutoAlert,
+    /// An admin resolved a flag.
+    Resolve,
+    /// The same text was posted by many users.
+    SpamWave,
+    /// A message reached a spam score threshold.
+    SpamScore,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Filter {
+    pub keyword: String,
+    /// Also match the keyword with separators and leetspeak in between.
+    #[serde(default)]
+    pub fuzzy: bool,
+    /// Id of the reason whose alert is posted.
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Scripts {
+    /// Scripts allowed in main group.
+    pub allowed: Vec<Script>,
+    /// Share of letters in another script for a message to be taken as
+    /// written in it.
+    #[serde(default = "default_scripts_confidence")]
+    pub confidence: f64,
+    /// Messages with fewer letters are never checked.
+    #[serde(default = "default_scripts_min_letters")]
+    pub min_letters: usize,
+    #[serde(default)]
+    pub action: ScriptAction,
+    /// Reply to messages if `action` is `remind`.
+    #[serde(default = "default_scripts_text")]
+    pub text: String,
+}
+
+/// Writing systems told apart by `scripts`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Script {
+    /// Chinese, also used in Japanese.
+    Han,
+    /// Japanese hiragana and katakana.
+    Kana,
+    Hangul,
+    Latin,
+    Cyrillic,
+    Arabic,
+    Other,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ScriptAction {
+    /// Tell admin group about the message.
+    #[default]
+    Notify,
+    /// Reply to the message with `text`, once per `user_reminder_cooldown`.
+    Remind,
+}
+
+/// While in attack mode, messages of spam waves are deleted, alerts in
+/// main group are suppressed and admins get a periodic summary instead.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Attack {
+    /// Spam waves within `window` to enter attack mode.
+    #[serde(default = "default_attack_waves")]
+    pub waves: usize,
+    /// Window in seconds that waves are counted in, attack mode is left
+    /// once no wave is detected for this long.
+    #[serde(default = "default_attack_window")]
+    pub window: u64,
+    /// Seconds between summaries posted to admin group in attack mode.
+    #[serde(default = "default_attack_summary_interval")]
+    pub summary_interval: u64,
+}
+
+/// Requests meeting all criteria are approved if `auto_approve` is set, the
+/// rest are posted to admin group with approve and decline buttons.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JoinRequests {
+    #[serde(default)]
+    pub auto_approve: bool,
+    /// Only users with ids up to this are approved automatically, 0 allows
+    /// any id. Telegram doesn't tell how old accounts are, but ids grow
+    /// over time, so this approximates a minimum account age.
+    #[serde(default)]
+    pub max_user_id: i64,
+    /// Decline users banned before right away instead of asking admins.
+    #[serde(default)]
+    pub decline_banned: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpamScore {
+    /// Score of a message posted right after the sender joined, fading to
+    /// 0 at the end of `join_window`.
+    #[serde(default)]
+    pub join: f64,
+    #[serde(default = "default_join_window")]
+    pub join_window: u64,
+    /// Score of each link in the message.
+    #[serde(default)]
+    pub link: f64,
+    /// Score of each earlier message with the same content from the sender
+    /// within `flood_window`.
+    #[serde(default)]
+    pub repeat: f64,
+    /// Score of each earlier message from the sender within `flood_window`.
+    #[serde(default)]
+    pub flood: f64,
+    #[serde(default = "default_flood_window")]
+    pub flood_window: u64,
+    /// Actions by the minimal score, only the action of the highest
+    /// threshold reached is taken.
+    #[serde(default)]
+    pub actions: Vec<ScoreAction>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScoreAction {
+    pub min: f64,
+    pub action: SpamAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SpamAction {
+    /// Notify admin group.
+    Notify,
+    /// Delete the message and notify admin group.
+    Delete,
+    /// Delete the message, ban the sender and notify admin group.
+    Ban,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Announce {
+    /// Chat that announcements are posted to, defaults to admin group.
+    #[serde(default)]
+    pub chat: Option<i64>,
+    #[serde(default = "default_online_text")]
+    pub online: String,
+    #[serde(default = "default_offline_text")]
+    pub offline: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Buttons {
+    /// Label of the button linking to `offtopic_group`.
+    #[serde(default = "default_offtopic_button")]
+    pub offtopic: String,
+    /// Label of the button linking to `meta_group`.
+    #[serde(default = "default_appeal_button")]
+    pub appeal: String,
+    /// Optional label of the button linking to rules synced by
+    /// `/syncrules`, no button if it's not set or rules are never synced.
+    #[serde(default)]
+    pub rules: Option<String>,
+    /// Language of main group like `en`, alerts are labeled in it if it's
+    /// one of `languages`, and with the labels above otherwise.
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Labels in other languages keyed by language codes, labels missing
+    /// in a language fall back to the ones above.
+    #[serde(default)]
+    pub languages: BTreeMap<String, ButtonLabels>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ButtonLabels {
+    #[serde(default)]
+    pub offtopic: Option<String>,
+    #[serde(default)]
+    pub appeal: Option<String>,
+    #[serde(default)]
+    pub rules: Option<String>,
+}
+
+impl Buttons {
+    /// Labels in `language`, `zh-hans` falls back to `zh`.
+    fn localized(&self) -> Option<&ButtonLabels> {
+        let lang = self.language.as_deref()?;
+        self.languages
+            .get(lang)
+            .or_else(|| lang.split('-').next().and_then(|l| self.languages.get(l)))
+    }
+
+    /// Label of the button linking to `offtopic_group`.
+    pub fn offtopic_label(&self) -> &str {
+        self.localized()
+            .and_then(|l| l.offtopic.as_deref())
+            .unwrap_or(&self.offtopic)
+    }
+
+    /// Label of the button linking to appeals.
+    pub fn appeal_label(&self) -> &str {
+        self.localized()
+            .and_then(|l| l.appeal.as_deref())
+            .unwrap_or(&self.appeal)
+    }
+
+    /// Label of the button linking to rules, `None` if there is no button.
+    pub fn rules_label(&self) -> Option<&str> {
+        let rules = self.rules.as_deref()?;
+        Some(self.localized().and_then(|l| l.rules.as_deref()).unwrap_or(rules))
+    }
+
+    /// All labels in every language, for checking them on start.
+    pub fn labels(&self) -> impl Iterator<Item = &String> {
+        let localized = self
+            .languages
+            .values()
+            .flat_map(|l| l.offtopic.iter().chain(&l.appeal).chain(&l.rules));
+        std::iter::once(&self.offtopic)
+            .chain(std::iter::once(&self.appeal))
+            .chain(&self.rules)
+            .chain(localized)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheMigration {
+    #[default]
+    Upgrade,
+    Discard,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Appeal {
+    /// Link to `meta_group`, or start an appeal in private chat if
+    /// `appeal_queue` is set.
+    #[default]
+    Meta,
+    /// Link to a private chat with the bot, appeals sent there are posted
+    /// to admin group.
+    Dm,
+    /// Link to `appeal_url` of the reason.
+    Url,
+    /// No appeal button.
+    None,
+}
+
+impl Default for Buttons {
+    fn default() -> Self {
+        Buttons {
+            offtopic: default_offtopic_button(),
+            appeal: default_appeal_button(),
+            rules: None,
+            language: None,
+            languages: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatTimezone {
+    pub chat: i64,
+    /// Offset in minutes from UTC.
+    pub utc_offset: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SummaryTarget {
+    /// Post to admin group.
+    #[default]
+    Admin,
+    /// Post to `log_channel`.
+    Log,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CooldownScope {
+    /// Per original message.
+    #[default]
+    Message,
+    /// Per admin, regardless of message.
+    Admin,
+    /// Per chat that alerts are posted to.
+    Group,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SelfFlag {
+    /// Flag as usual.
+    #[default]
+    Allow,
+    /// Warn the admin but still flag.
+    Warn,
+    /// Refuse to flag.
+    Block,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EditedCommands {
+    /// Ignore the command, only commands in new messages are handled.
+    #[default]
+    Ignore,
+    /// Handle the command, unless the same command has been handled for
+    /// the message already so that repeated edits don't run it again.
+    Process,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MentionAction {
+    #[default]
+    Ignore,
+    /// Reply with `mention_text`.
+    Reply,
+    /// List commands to admins, others get `mention_text`.
+    Help,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LabelStyle {
+    /// Only `label`.
+    #[default]
+    Primary,
+    /// `label` followed by all `labels`, like `离题 / Off-topic`.
+    Both,
+    /// The label in the language of the admin, `label` if there is none.
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CallbackAdminCheck {
+    /// Ask telegram on every press, cached status is used only if telegram
+    /// can't be reached.
+    #[default]
+    Recheck,
+    /// Trust that whoever can see the prompt was an admin when it was sent.
+    Trust,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AdminSource {
+    /// Admins of main group.
+    #[default]
+    Main,
+    /// Admins of admin group.
+    Admin,
+    /// Admins of either group.
+    Both,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CallbackFormat {
+    /// Plain JSON, readable in logs.
+    #[default]
+    Json,
+    /// MessagePack in url-safe base64.
+    Compact,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+    /// Exit with an error.
+    #[default]
+    Exit,
+    /// Log the error and keep polling.
+    Retry,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheFallback {
+    /// Refuse to start.
+    #[default]
+    Fail,
+    /// Start with an in-memory cache, all cached data is lost on exit.
+    Memory,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MentionStyle {
+    /// `@username` if available, otherwise a text mention.
+    Mention,
+    /// Text link to `tg://user?id=`.
+    Link,
+    /// Plain name, never notifies.
+    #[default]
+    Plain,
+}
+
+fn default_reasons() -> Vec<Reason> {
+    vec![Reason {
+        id: "offtopic".to_string(),
+        label: "离题".to_string(),
+        text: "请勿进行离题讨论，#archlinux-cn 仅用于 archlinux 相关话题讨论，无关主题请前往 OT 群"
+            .to_string(),
+        labels: BTreeMap::new(),
+        target: Target::Main,
+        appeal: Appeal::Meta,
+        appeal_url: None,
+        requires_confirm: false,
+        kinds: Vec::new(),
+        audit_quote: None,
+        dm_template: None,
+        counts_toward_escalation: None,
+        log_level: None,
+    }]
+}
+
+fn default_hook_timeout() -> u64 {
+    10
+}
+
+fn default_online_text() -> String {
+    "管理机器人已上线".to_string()
+}
+
+fn default_offline_text() -> String {
+    "管理机器人已下线".to_string()
+}
+
+fn default_offtopic_button() -> String {
+    "跳转到 OT 群".to_string()
+}
+
+fn default_appeal_button() -> String {
+    "申诉".to_string()
+}
+
+fn default_scripts_confidence() -> f64 {
+    0.8
+}
+
+fn default_scripts_min_letters() -> usize {
+    20
+}
+
+fn default_scripts_text() -> String {
+    "请使用中文交流".to_string()
+}
+
+fn default_callback_window() -> u64 {
+    10
+}
+
+fn default_callback_limit() -> usize {
+    3
+}
+
+fn default_callback_admin_limit() -> usize {
+    20
+}
+
+fn default_alert_cooldown_limit() -> usize {
+    1
+}
+
+fn default_cooldown_text() -> String {
+    "操作过于频繁，请在 {secs} 秒后再试".to_string()
+}
+
+fn default_hidden_user_text() -> String {
+    "该消息来源隐藏，部分功能不可用".to_string()
+}
+
+fn default_deadletter_interval() -> u64 {
+    300
+}
+
+fn default_deadletter_max() -> usize {
+    100
+}
+
+fn default_deadletter_max_age() -> u64 {
+    86400
+}
+
+fn default_forward_date_tolerance() -> u64 {
+    1
+}
+
+fn default_admin_check_interval() -> u64 {
+    60
+}
+
+fn default_confirm_timeout() -> u64 {
+    60
+}
+
+fn default_audit_quote() -> bool {
+    true
+}
+
+fn default_counts_toward_escalation() -> bool {
+    true
+}
+
+fn default_normalize_text() -> bool {
+    true
+}
+
+fn default_appeal_text() -> String {
+    "请直接发送申诉内容，包括被提醒的消息和理由，管理员会尽快处理".to_string()
+}
+
+fn default_mention_text() -> String {
+    "请私聊转发需要处理的消息给我".to_string()
+}
+
+fn default_confirm_commands() -> Vec<String> {
+    vec!["forgive".to_string(), "retry_deadletter".to_string()]
+}
+
+fn default_album_caption() -> bool {
+    true
+}
+
+fn default_audit_max() -> usize {
+    10
+}
+
+fn default_batch_max() -> usize {
+    10
+}
+
+fn default_sticker_window() -> u64 {
+    60
+}
+
+fn default_spam_wave_window() -> u64 {
+    300
+}
+
+fn default_spam_wave_min_len() -> usize {
+    10
+}
+
+fn default_attack_waves() -> usize {
+    2
+}
+
+fn default_attack_window() -> u64 {
+    600
+}
+
+fn default_attack_summary_interval() -> u64 {
+    300
+}
+
+fn default_join_window() -> u64 {
+    86400
+}
+
+fn default_flood_window() -> u64 {
+    60
+}
+
+fn default_oncall_interval() -> u64 {
+    3600
+}
+
+fn default_prune_batch() -> usize {
+    1000
+}
+
+fn default_prune_pause() -> u64 {
+    100
+}
+
+fn default_shutdown_timeout() -> u64 {
+    10
+}
+
+fn default_poll_timeout() -> u64 {
+    5
+}
+
+fn default_fetch_backoff_max() -> u64 {
+    60
+}
+
+fn default_api_burst() -> u64 {
+    30
+}
+
+fn default_max_cached_text_len() -> usize {
+    1024
+}
+
+fn default_stats_interval() -> u64 {
+    3600
+}
+
+fn default_resolved_retention() -> u64 {
+    30
+}
+
+fn default_stats_retention() -> u64 {
+    90
 }
@@ -0,0 +1,28 @@
+use std::fmt;
+
+/// Categories of errors that need a human to look into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Critical {
+    /// Cache can't be opened and the bot is running on in-memory cache.
+    CacheOpen,
+    /// Another instance is polling with the same token.
+    Conflict,
+    /// Token has been revoked or the bot has been deleted.
+    Unauthorized,
+    /// A failed action has been given up after retries.
+    ActionDropped,
+    /// A ban or an audit can't be recorded.
+    WriteFailed,
+}
+
+impl fmt::Display for Critical {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Critical::CacheOpen => write!(f, "缓存打开失败"),
+            Critical::Conflict => write!(f, "多实例冲突"),
+            Critical::Unauthorized => write!(f, "token 失效"),
+            Critical::ActionDropped => write!(f, "操作重试失败"),
+            Critical::WriteFailed => write!(f, "记录写入失败"),
+        }
+    }
+}
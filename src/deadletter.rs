@@ -0,0 +1,18 @@
+use serde::{Deserialize, Serialize};
+use telegram_bot::{Integer, MessageId};
+
+/// Actions that can be replayed after failing.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Action {
+    Alert { id: MessageId, reason: String },
+}
+
+/// An action that failed to execute, kept for later retries.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeadLetter {
+    pub action: Action,
+    /// Unix time when the action failed at the first time.
+    pub time: Integer,
+    pub attempts: u32,
+    pub error: String,
+}
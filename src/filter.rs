@@ -0,0 +1,100 @@
+use super::config::Filter;
+
+/// Whether the text contains the keyword of the filter.
+///
+/// Exact filters match case-insensitively. Fuzzy filters also ignore
+/// separators, common leetspeak and Cyrillic or Greek lookalikes, so
+/// `s p 4 m` and `ѕрам` match `spam`, at the cost of matching across word
+/// boundaries.
+pub fn matches(filter: &Filter, text: &str) -> bool {
+    if filter.fuzzy {
+        let keyword = fold(&filter.keyword);
+        !keyword.is_empty() && fold(text).contains(&keyword)
+    } else {
+        text.to_lowercase().contains(&filter.keyword.to_lowercase())
+    }
+}
+
+/// Fold text for fuzzy matching: lowercase, map leetspeak and lookalikes
+/// to Latin letters and drop everything but letters and digits.
+pub fn fold(text: &str) -> String {
+    text.chars()
+        .flat_map(char::to_lowercase)
+        .map(unleet)
+        .map(unconfuse)
+        .filter(|c| c.is_alphanumeric())
+        .collect()
+}
+
+fn unleet(c: char) -> char {
+    match c {
+        '4' | '@' => 'a',
+        '8' => 'b',
+        '3' => 'e',
+        '6' | '9' => 'g',
+        '1' | '!' | '|' => 'i',
+        '0' => 'o',
+        '5' | '$' => 's',
+        '7' | '+' => 't',
+        '2' => 'z',
+        c => c,
+    }
+}
+
+/// Map lowercase Cyrillic and Greek letters that look like Latin ones.
+fn unconfuse(c: char) -> char {
+    match c {
+        'а' | 'α' => 'a',
+        'в' | 'β' => 'b',
+        'с' | 'ϲ' => 'c',
+        'е' | 'ε' => 'e',
+        'н' | 'η' => 'h',
+        'і' | 'ι' => 'i',
+        'ј' => 'j',
+        'к' | 'κ' => 'k',
+        'м' => 'm',
+        'о' | 'ο' => 'o',
+        'р' | 'ρ' => 'p',
+        'ѕ' => 's',
+        'т' | 'τ' => 't',
+        'υ' => 'u',
+        'ν' => 'v',
+        'х' | 'χ' => 'x',
+        'у' | 'γ' => 'y',
+        c => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::normalize::normalize;
+
+    fn filter(keyword: &str, fuzzy: bool) -> Filter {
+        Filter {
+            keyword: keyword.to_string(),
+            fuzzy,
+            reason: "spam".to_string(),
+        }
+    }
+
+    #[test]
+    fn fuzzy_matches_evasions() {
+        let spam = filter("spam", true);
+        for text in ["SPAM", "s p a m", "s.p-a_m", "sp4m", "$p@m", "ѕрам", "s p α м"].iter() {
+            assert!(matches(&spam, text), "{}", text);
+        }
+        // Fullwidth letters are left to `normalize`.
+        assert!(matches(&spam, &normalize("ｓｐａｍ")));
+        assert!(!matches(&spam, "span"));
+        assert!(!matches(&filter("...", true), "anything"));
+    }
+
+    #[test]
+    fn exact_ignores_case_only() {
+        let spam = filter("spam", false);
+        assert!(matches(&spam, "no SPAM here"));
+        assert!(!matches(&spam, "s p a m"));
+        assert!(!matches(&spam, "sp4m"));
+    }
+}
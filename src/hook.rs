@@ -0,0 +1,100 @@
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use hyper::{Body, Client, Method, Request};
+use hyper_tls::HttpsConnector;
+use log::{debug, error, info};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::time;
+
+use super::config::{Event, Hook};
+
+// Output of commands is truncated in logs.
+const MAX_LOGGED_OUTPUT: usize = 1024;
+
+/// Fire all hooks attached to the event in background, results are only
+/// logged so that hooks never block or fail the action.
+pub fn fire(hooks: &[Hook], event: Event, payload: &serde_json::Value) {
+    for hook in hooks.iter().filter(|h| h.events.contains(&event)) {
+        let hook = hook.clone();
+        let body = payload.to_string();
+        tokio::spawn(async move {
+            let timeout = Duration::from_secs(hook.timeout);
+            match time::timeout(timeout, run(&hook, body)).await {
+                Ok(Ok(_)) => debug!("hook {:?} for {:?} finished", hook, event),
+                Ok(Err(err)) => error!("hook {:?} for {:?}: {}", hook, event, err),
+                Err(_) => error!("hook {:?} for {:?} timed out", hook, event),
+            }
+        });
+    }
+}
+
+async fn run(hook: &Hook, body: String) -> Result<()> {
+    if let Some((program, args)) = hook.command.split_first() {
+        exec(program, args, &body).await?;
+    }
+    if let Some(url) = &hook.url {
+        post(url, body).await?;
+    }
+    Ok(())
+}
+
+// Commands are run without a shell and with a cleared environment, they are
+// killed once the hook times out.
+async fn exec(program: &str, args: &[String], body: &str) -> Result<()> {
+    let mut child = Command::new(program)
+        .args(args)
+        .env_clear()
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(body.as_bytes()).await?;
+    }
+    let output = child.wait_with_output().await?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    info!(
+        "hook {} exited with {}, stdout: {}, stderr: {}",
+        program,
+        output.status,
+        truncate(&stdout),
+        truncate(&stderr)
+    );
+    if !output.status.success() {
+        return Err(anyhow!("{} exited with {}", program, output.status));
+    }
+    Ok(())
+}
+
+async fn post(url: &str, body: String) -> Result<()> {
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header("content-type", "application/json")
+        .body(Body::from(body))?;
+
+    let resp = client.request(req).await?;
+    if !resp.status().is_success() {
+        return Err(anyhow!("{} responded with {}", url, resp.status()));
+    }
+    Ok(())
+}
+
+fn truncate(s: &str) -> String {
+    let s = s.trim();
+    if s.chars().count() > MAX_LOGGED_OUTPUT {
+        let mut t: String = s.chars().take(MAX_LOGGED_OUTPUT).collect();
+        t.push('…');
+        t
+    } else {
+        s.to_string()
+    }
+}
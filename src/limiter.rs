@@ -0,0 +1,123 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+use telegram_bot::Integer;
+
+// Keys are pruned once the limiter tracks more keys than this.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Sliding window limiter that allows `limit` hits per key in `window`
+/// seconds.
+///
+/// A limiter with zero window or zero limit never limits.
+pub struct Limiter<K> {
+    window: Integer,
+    limit: usize,
+    hits: HashMap<K, VecDeque<Integer>>,
+}
+
+impl<K: Hash + Eq> Limiter<K> {
+    pub fn new(window: Integer, limit: usize) -> Self {
+        Limiter {
+            window,
+            limit,
+            hits: HashMap::new(),
+        }
+    }
+
+    /// Record a hit of `key` at `now`.
+    ///
+    /// Returns the seconds to wait until next hit is allowed if `key` has
+    /// reached the limit, the hit is not recorded in this case.
+    pub fn hit(&mut self, key: K, now: Integer) -> Result<(), Integer> {
+        if self.window <= 0 || self.limit == 0 {
+            return Ok(());
+        }
+
+        let window = self.window;
+        if self.hits.len() > PRUNE_THRESHOLD {
+            self.hits
+                .retain(|_, v| v.back().is_some_and(|t| now - t < window));
+        }
+
+        let hits = self.hits.entry(key).or_default();
+        while hits.front().is_some_and(|t| now - t >= window) {
+            hits.pop_front();
+        }
+
+        if hits.len() >= self.limit {
+            return Err(hits.front().map_or(0, |t| t + window - now));
+        }
+
+        hits.push_back(now);
+        Ok(())
+    }
+
+    /// Returns hits of every key in the window at `now`, for persisting.
+    pub fn state(&self, now: Integer) -> Vec<(K, Vec<Integer>)>
+    where
+        K: Clone,
+    {
+        let window = self.window;
+        let mut state = Vec::with_capacity(self.hits.len());
+        for (k, hits) in self.hits.iter() {
+            let hits: Vec<_> = hits.iter().copied().filter(|t| now - t < window).collect();
+            if !hits.is_empty() {
+                state.push((k.clone(), hits));
+            }
+        }
+        state
+    }
+
+    /// Restore hits returned by `state`, hits out of the window at `now`
+    /// are dropped since hits are kept in unix time.
+    pub fn restore(&mut self, state: Vec<(K, Vec<Integer>)>, now: Integer) {
+        let window = self.window;
+        for (k, hits) in state {
+            let hits: VecDeque<_> = hits.into_iter().filter(|t| now - t < window).collect();
+            if !hits.is_empty() {
+                self.hits.insert(k, hits);
+            }
+        }
+    }
+
+    /// Forget all hits of `key`, returns whether there were any.
+    pub fn reset(&mut self, key: &K) -> bool {
+        self.hits.remove(key).is_some_and(|hits| !hits.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hit_within_window() {
+        let mut limiter = Limiter::new(60, 2);
+        assert_eq!(limiter.hit(1, 0), Ok(()));
+        assert_eq!(limiter.hit(1, 10), Ok(()));
+        assert_eq!(limiter.hit(1, 20), Err(40));
+        assert_eq!(limiter.hit(2, 20), Ok(()));
+        assert_eq!(limiter.hit(1, 60), Ok(()));
+
+        let mut unlimited = Limiter::new(0, 1);
+        assert_eq!(unlimited.hit(1, 0), Ok(()));
+        assert_eq!(unlimited.hit(1, 0), Ok(()));
+    }
+
+    #[test]
+    fn restore_saved_state() {
+        let mut limiter = Limiter::new(60, 1);
+        limiter.hit(1, 0).unwrap();
+        limiter.hit(2, 50).unwrap();
+        let state = limiter.state(30);
+        assert_eq!(state.len(), 2);
+
+        // Hits of key 1 have left the window by the time it's restored.
+        let mut restored = Limiter::new(60, 1);
+        restored.restore(state, 70);
+        assert_eq!(restored.hit(1, 70), Ok(()));
+        assert_eq!(restored.hit(2, 70), Err(40));
+        assert!(restored.state(200).is_empty());
+    }
+}
@@ -1,17 +1,33 @@
-use std::env;
 use std::fs;
 
 use anyhow::Result;
-use clap::{crate_authors, crate_version, Clap};
+use clap::Clap;
 
 mod api;
+mod budget;
 mod cache;
+mod codec;
+mod command;
 mod config;
+mod critical;
+mod deadletter;
+mod filter;
+mod hook;
+mod limiter;
+mod normalize;
+mod requests;
+mod script;
+mod spam_score;
+mod stats;
+mod wave;
 
 #[derive(Clap)]
 struct Opts {
     #[clap(short, long)]
     config: String,
+    /// Run the self-test and exit, non-zero if it fails.
+    #[clap(long)]
+    selftest: bool,
 }
 
 #[tokio::main]
@@ -24,5 +40,8 @@ async fn main() -> Result<()> {
 
     let api = api::API::new(cfg).await?;
 
+    if opts.selftest {
+        return api.selftest().await;
+    }
     api.run().await
 }
@@ -0,0 +1,75 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Clean up text before matching and quoting it.
+///
+/// Zero-width characters, bidi controls and other control characters are
+/// dropped since they are used to evade matching or to garble quotes, and
+/// fullwidth ASCII is folded into ASCII. This is not a full NFKC.
+pub fn normalize(text: &str) -> String {
+    text.chars()
+        .filter(|c| !is_invisible(*c))
+        .map(fold_fullwidth)
+        .collect()
+}
+
+fn is_invisible(c: char) -> bool {
+    match c {
+        '\n' | '\t' => false,
+        // Zero-width spaces and joiners, LRM and RLM.
+        '\u{200B}'..='\u{200F}' => true,
+        // Bidi embeddings, overrides and isolates.
+        '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' => true,
+        // Word joiner and invisible operators.
+        '\u{2060}'..='\u{2064}' => true,
+        '\u{FEFF}' | '\u{00AD}' | '\u{180E}' => true,
+        c => c.is_control(),
+    }
+}
+
+fn fold_fullwidth(c: char) -> char {
+    match c {
+        '\u{FF01}'..='\u{FF5E}' => std::char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+        '\u{3000}' => ' ',
+        c => c,
+    }
+}
+
+/// Hash of the text for telling repeated contents apart, case and
+/// whitespace are ignored since they are cheap to vary.
+pub fn content_hash(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for word in text.split_whitespace() {
+        word.to_lowercase().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_invisible_characters() {
+        // Zero-width space, joiner, BOM and soft hyphen inside the word.
+        assert_eq!(normalize("s\u{200B}p\u{200D}a\u{FEFF}m\u{00AD}"), "spam");
+        // Bidi override shows `moc.evil` as `live.com`.
+        assert_eq!(normalize("\u{202E}moc.live\u{202C}"), "moc.live");
+        assert_eq!(normalize("\u{2066}free\u{2069} coin\u{0007}"), "free coin");
+        assert_eq!(normalize("line\nnext\tcol"), "line\nnext\tcol");
+    }
+
+    #[test]
+    fn fold_fullwidth_lookalikes() {
+        assert_eq!(normalize("ｓｐａｍ！"), "spam!");
+        assert_eq!(normalize("ＦＲＥＥ\u{3000}ＣＯＩＮ"), "FREE COIN");
+        assert_eq!(normalize("中文，不变"), "中文,不变");
+    }
+
+    #[test]
+    fn hash_ignores_spacing_and_case() {
+        assert_eq!(content_hash("Buy  NOW\n"), content_hash("buy now"));
+        assert_eq!(content_hash(&normalize("buy\u{3000}now")), content_hash("buy now"));
+        assert_ne!(content_hash("buy now"), content_hash("buynow"));
+    }
+}
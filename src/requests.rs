@@ -0,0 +1,369 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use telegram_bot::*;
+
+/// Permissions of a member in a supergroup.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ChatPermissions {
+    pub can_send_messages: bool,
+    pub can_send_media_messages: bool,
+    pub can_send_polls: bool,
+    pub can_send_other_messages: bool,
+    pub can_add_web_page_previews: bool,
+    pub can_change_info: bool,
+    pub can_invite_users: bool,
+    pub can_pin_messages: bool,
+}
+
+impl ChatPermissions {
+    /// Permissions that lift all restrictions, members are still limited by
+    /// the default permissions of the group.
+    pub fn all() -> Self {
+        ChatPermissions {
+            can_send_messages: true,
+            can_send_media_messages: true,
+            can_send_polls: true,
+            can_send_other_messages: true,
+            can_add_web_page_previews: true,
+            can_change_info: true,
+            can_invite_users: true,
+            can_pin_messages: true,
+        }
+    }
+
+    /// Permissions that mute the member.
+    pub fn none() -> Self {
+        ChatPermissions {
+            can_send_messages: false,
+            can_send_media_messages: false,
+            can_send_polls: false,
+            can_send_other_messages: false,
+            can_add_web_page_previews: false,
+            can_change_info: false,
+            can_invite_users: false,
+            can_pin_messages: false,
+        }
+    }
+
+    /// Permissions of a restricted member, telegram-bot doesn't parse the
+    /// restricted status or `can_send_polls`, which follows
+    /// `can_send_other_messages` here.
+    pub fn of_restricted(member: &ChatMember) -> Self {
+        let other = member.can_send_other_messages.unwrap_or(true);
+        ChatPermissions {
+            can_send_messages: member.can_send_messages.unwrap_or(true),
+            can_send_media_messages: member.can_send_media_messages.unwrap_or(true),
+            can_send_polls: other,
+            can_send_other_messages: other,
+            can_add_web_page_previews: member.can_add_web_page_previews.unwrap_or(true),
+            can_change_info: member.can_change_info.unwrap_or(true),
+            can_invite_users: member.can_invite_users.unwrap_or(true),
+            can_pin_messages: member.can_pin_messages.unwrap_or(true),
+        }
+    }
+}
+
+/// Use this method to restrict a user in a supergroup, telegram-bot doesn't
+/// provide it yet.
+#[derive(Debug, Clone, Serialize)]
+#[must_use = "requests do nothing unless sent"]
+pub struct RestrictChatMember {
+    chat_id: ChatRef,
+    user_id: UserId,
+    permissions: ChatPermissions,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    until_date: Option<Integer>,
+}
+
+impl Request for RestrictChatMember {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonTrueToUnitResponse;
+
+    fn serialize(&self) -> Result<HttpRequest, types::Error> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("restrictChatMember"), self)
+    }
+}
+
+impl RestrictChatMember {
+    pub fn new<C, U>(chat: C, user: U, permissions: ChatPermissions) -> Self
+    where
+        C: ToChatRef,
+        U: ToUserId,
+    {
+        RestrictChatMember {
+            chat_id: chat.to_chat_ref(),
+            user_id: user.to_user_id(),
+            permissions,
+            until_date: None,
+        }
+    }
+
+    /// Time restrictions are lifted by telegram, restrictions are forever
+    /// if it's not set.
+    pub fn until_date(&mut self, until: Integer) -> &mut Self {
+        self.until_date = Some(until);
+        self
+    }
+}
+
+/// Use this method to get the pinned message of a chat, `GetChat` of
+/// telegram-bot doesn't parse it.
+#[derive(Debug, Clone, Serialize)]
+#[must_use = "requests do nothing unless sent"]
+pub struct GetPinnedMessage {
+    chat_id: ChatRef,
+}
+
+impl Request for GetPinnedMessage {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonIdResponse<PinnedChat>;
+
+    fn serialize(&self) -> Result<HttpRequest, types::Error> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("getChat"), self)
+    }
+}
+
+impl GetPinnedMessage {
+    pub fn new<C>(chat: C) -> Self
+    where
+        C: ToChatRef,
+    {
+        GetPinnedMessage {
+            chat_id: chat.to_chat_ref(),
+        }
+    }
+}
+
+/// The part of a chat `GetPinnedMessage` cares about.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PinnedChat {
+    pub pinned_message: Option<PinnedMessage>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PinnedMessage {
+    pub message_id: MessageId,
+    pub text: Option<String>,
+    pub caption: Option<String>,
+}
+
+/// Use this method to unpin a message, unlike `UnpinChatMessage` which
+/// always unpins the latest pinned message.
+#[derive(Debug, Clone, Serialize)]
+#[must_use = "requests do nothing unless sent"]
+pub struct UnpinMessage {
+    chat_id: ChatRef,
+    message_id: MessageId,
+}
+
+impl Request for UnpinMessage {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonTrueToUnitResponse;
+
+    fn serialize(&self) -> Result<HttpRequest, types::Error> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("unpinChatMessage"), self)
+    }
+}
+
+impl UnpinMessage {
+    pub fn new<C, M>(chat: C, message: M) -> Self
+    where
+        C: ToChatRef,
+        M: ToMessageId,
+    {
+        UnpinMessage {
+            chat_id: chat.to_chat_ref(),
+            message_id: message.to_message_id(),
+        }
+    }
+}
+
+/// Use this method to receive updates with long polling, `GetUpdates` of
+/// telegram-bot hides error codes of failed polls.
+#[derive(Debug, Clone, Serialize)]
+#[must_use = "requests do nothing unless sent"]
+pub struct GetRawUpdates {
+    offset: Integer,
+    timeout: Integer,
+}
+
+impl Request for GetRawUpdates {
+    type Type = JsonRequestType<Self>;
+    type Response = RawUpdatesResponse;
+
+    fn serialize(&self) -> Result<HttpRequest, types::Error> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("getUpdates"), self)
+    }
+}
+
+impl GetRawUpdates {
+    pub fn new(offset: Integer, timeout: Integer) -> Self {
+        GetRawUpdates { offset, timeout }
+    }
+}
+
+/// Updates as JSON values, or the error telegram returned for the poll.
+pub struct RawUpdatesResponse;
+
+#[derive(Deserialize)]
+struct RawResponse {
+    ok: bool,
+    result: Option<Vec<serde_json::Value>>,
+    error_code: Option<Integer>,
+    description: Option<String>,
+}
+
+impl ResponseType for RawUpdatesResponse {
+    type Type = Result<Vec<serde_json::Value>, ApiError>;
+
+    fn deserialize(resp: HttpResponse) -> Result<Self::Type, types::Error> {
+        let raw = resp
+            .body
+            .as_ref()
+            .and_then(|body| serde_json::from_slice::<RawResponse>(body).ok());
+        match raw {
+            Some(RawResponse {
+                ok: true,
+                result: Some(updates),
+                ..
+            }) => Ok(Ok(updates)),
+            Some(RawResponse {
+                ok: false,
+                error_code: Some(code),
+                description,
+                ..
+            }) => Ok(Err(ApiError {
+                code,
+                description: description.unwrap_or_default(),
+            })),
+            // Leave malformed bodies to telegram-bot to report.
+            _ => JsonIdResponse::<Vec<serde_json::Value>>::deserialize(resp).map(Ok),
+        }
+    }
+}
+
+/// Error returned by telegram along with its code.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub code: Integer,
+    pub description: String,
+}
+
+impl ApiError {
+    /// Token has been revoked or the bot has been deleted.
+    pub const UNAUTHORIZED: Integer = 401;
+    /// Another instance is polling with the same token.
+    pub const CONFLICT: Integer = 409;
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (code {})", self.description, self.code)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Request to join a chat, telegram-bot drops these updates.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChatJoinRequest {
+    pub chat: JoinChat,
+    pub from: User,
+    pub bio: Option<String>,
+}
+
+/// The part of the requested chat `ChatJoinRequest` cares about.
+#[derive(Debug, Clone, Deserialize)]
+pub struct JoinChat {
+    pub id: i64,
+}
+
+/// Update polled by `GetRawUpdates`, including kinds telegram-bot doesn't
+/// know.
+#[derive(Debug)]
+pub enum RawUpdate {
+    Update(Box<Update>),
+    ChatJoinRequest { id: Integer, request: ChatJoinRequest },
+}
+
+impl RawUpdate {
+    pub fn parse(value: serde_json::Value) -> serde_json::Result<Self> {
+        #[derive(Deserialize)]
+        struct Join {
+            update_id: Integer,
+            chat_join_request: ChatJoinRequest,
+        }
+
+        if value.get("chat_join_request").is_some() {
+            let join: Join = serde_json::from_value(value)?;
+            return Ok(RawUpdate::ChatJoinRequest {
+                id: join.update_id,
+                request: join.chat_join_request,
+            });
+        }
+        Ok(RawUpdate::Update(Box::new(serde_json::from_value(value)?)))
+    }
+}
+
+/// Use this method to approve a request to join a chat, telegram-bot
+/// doesn't provide it yet.
+#[derive(Debug, Clone, Serialize)]
+#[must_use = "requests do nothing unless sent"]
+pub struct ApproveChatJoinRequest {
+    chat_id: ChatRef,
+    user_id: UserId,
+}
+
+impl Request for ApproveChatJoinRequest {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonTrueToUnitResponse;
+
+    fn serialize(&self) -> Result<HttpRequest, types::Error> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("approveChatJoinRequest"), self)
+    }
+}
+
+impl ApproveChatJoinRequest {
+    pub fn new<C, U>(chat: C, user: U) -> Self
+    where
+        C: ToChatRef,
+        U: ToUserId,
+    {
+        ApproveChatJoinRequest {
+            chat_id: chat.to_chat_ref(),
+            user_id: user.to_user_id(),
+        }
+    }
+}
+
+/// Use this method to decline a request to join a chat, telegram-bot
+/// doesn't provide it yet.
+#[derive(Debug, Clone, Serialize)]
+#[must_use = "requests do nothing unless sent"]
+pub struct DeclineChatJoinRequest {
+    chat_id: ChatRef,
+    user_id: UserId,
+}
+
+impl Request for DeclineChatJoinRequest {
+    type Type = JsonRequestType<Self>;
+    type Response = JsonTrueToUnitResponse;
+
+    fn serialize(&self) -> Result<HttpRequest, types::Error> {
+        <Self::Type as RequestType>::serialize(RequestUrl::method("declineChatJoinRequest"), self)
+    }
+}
+
+impl DeclineChatJoinRequest {
+    pub fn new<C, U>(chat: C, user: U) -> Self
+    where
+        C: ToChatRef,
+        U: ToUserId,
+    {
+        DeclineChatJoinRequest {
+            chat_id: chat.to_chat_ref(),
+            user_id: user.to_user_id(),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+
+use super::config::Script;
+
+/// Returns the script most letters of the text are written in and its
+/// share of the letters, `None` if the text has fewer than `min_letters`
+/// letters.
+///
+/// Scripts stand in for languages here, which is cheap and good enough to
+/// tell Chinese from Russian but not English from French. Links, inline
+/// code and words that look like code are skipped since they are Latin
+/// whatever the language around them is.
+pub fn detect(text: &str, min_letters: usize) -> Option<(Script, f64)> {
+    let mut counts: HashMap<Script, usize> = HashMap::new();
+    let mut total = 0;
+    for (i, part) in text.split('`').enumerate() {
+        // Odd parts are between backticks.
+        if i % 2 == 1 {
+            continue;
+        }
+        for word in part.split_whitespace().filter(|w| !is_code(w)) {
+            for c in word.chars().filter(|c| c.is_alphabetic()) {
+                *counts.entry(script(c)).or_default() += 1;
+                total += 1;
+            }
+        }
+    }
+    if total == 0 || total < min_letters {
+        return None;
+    }
+
+    let (script, n) = counts.into_iter().max_by_key(|(_, n)| *n)?;
+    Some((script, n as f64 / total as f64))
+}
+
+fn is_code(word: &str) -> bool {
+    word.contains("://")
+        || word.contains("::")
+        || word.contains('_')
+        || word.contains('/')
+        || word.chars().any(|c| "{}()[]<>=;$".contains(c))
+}
+
+fn script(c: char) -> Script {
+    match c {
+        'a'..='z' | 'A'..='Z' | '\u{00C0}'..='\u{024F}' => Script::Latin,
+        '\u{0400}'..='\u{04FF}' => Script::Cyrillic,
+        '\u{0600}'..='\u{06FF}' => Script::Arabic,
+        '\u{3040}'..='\u{30FF}' => Script::Kana,
+        '\u{1100}'..='\u{11FF}' | '\u{AC00}'..='\u{D7AF}' => Script::Hangul,
+        '\u{3400}'..='\u{4DBF}' | '\u{4E00}'..='\u{9FFF}' | '\u{F900}'..='\u{FAFF}' => Script::Han,
+        _ => Script::Other,
+    }
+}
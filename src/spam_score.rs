@@ -0,0 +1,100 @@
+use std::collections::{HashMap, VecDeque};
+
+use telegram_bot::{Integer, UserId};
+
+use super::config;
+use super::normalize::content_hash;
+
+// Users are pruned once more users than this are tracked.
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// Weighted spam score of a message and the part of each signal.
+#[derive(Debug, Clone)]
+pub struct Score {
+    pub total: f64,
+    pub parts: Vec<(&'static str, f64)>,
+}
+
+/// Combines signals of messages in main group into spam scores.
+pub struct Scorer {
+    cfg: config::SpamScore,
+    // Time that users joined main group.
+    joins: HashMap<UserId, Integer>,
+    // Recent messages of each user and hashes of their contents, oldest
+    // first.
+    history: HashMap<UserId, VecDeque<(Integer, u64)>>,
+}
+
+impl Scorer {
+    pub fn new(cfg: config::SpamScore) -> Self {
+        Scorer {
+            cfg,
+            joins: HashMap::new(),
+            history: HashMap::new(),
+        }
+    }
+
+    /// Record the user joining main group.
+    pub fn join(&mut self, user: UserId, now: Integer) {
+        let window = self.cfg.join_window as Integer;
+        if self.joins.len() > PRUNE_THRESHOLD {
+            self.joins.retain(|_, t| now - *t < window);
+        }
+        self.joins.insert(user, now);
+    }
+
+    /// Record a message of the user and returns its score.
+    pub fn observe(&mut self, user: UserId, text: &str, links: usize, now: Integer) -> Score {
+        let mut parts = Vec::new();
+
+        let join_window = self.cfg.join_window as Integer;
+        if let Some(joined) = self.joins.get(&user) {
+            let age = now - joined;
+            if age < join_window {
+                // Fresh members score the most, decreasing to 0 at the end
+                // of the window.
+                let recency = 1.0 - age.max(0) as f64 / join_window as f64;
+                parts.push(("join", self.cfg.join * recency));
+            }
+        }
+
+        if links > 0 {
+            parts.push(("link", self.cfg.link * links as f64));
+        }
+
+        let window = self.cfg.flood_window as Integer;
+        if self.history.len() > PRUNE_THRESHOLD {
+            self.history
+                .retain(|_, v| v.back().is_some_and(|(t, _)| now - t < window));
+        }
+        // Empty contents like stickers are all the same.
+        let key = content_hash(text);
+        let history = self.history.entry(user).or_default();
+        while history.front().is_some_and(|(t, _)| now - t >= window) {
+            history.pop_front();
+        }
+        let repeats = history.iter().filter(|(_, k)| *k == key).count();
+        if repeats > 0 {
+            parts.push(("repeat", self.cfg.repeat * repeats as f64));
+        }
+        if !history.is_empty() {
+            parts.push(("flood", self.cfg.flood * history.len() as f64));
+        }
+        history.push_back((now, key));
+
+        Score {
+            total: parts.iter().map(|(_, v)| v).sum(),
+            parts,
+        }
+    }
+
+    /// Returns the action of the highest threshold reached by the score.
+    pub fn action(&self, score: &Score) -> Option<config::SpamAction> {
+        self.cfg
+            .actions
+            .iter()
+            .filter(|a| score.total >= a.min)
+            .max_by(|a, b| a.min.total_cmp(&b.min))
+            .map(|a| a.action)
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use telegram_bot::{Integer, MessageId, UserId};
+
+/// Counters collected while handling updates.
+///
+/// Counters are cumulative: on startup they are restored from the latest
+/// snapshot so that deltas between snapshots stay meaningful across restarts.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct Counters {
+    pub messages_cached: u64,
+    pub prompts_sent: u64,
+    pub callbacks_handled: u64,
+    pub alerts_sent: u64,
+}
+
+impl Counters {
+    /// Returns the activity that happened between `earlier` and `self`.
+    pub fn since(&self, earlier: &Counters) -> Counters {
+        Counters {
+            messages_cached: self.messages_cached.saturating_sub(earlier.messages_cached),
+            prompts_sent: self.prompts_sent.saturating_sub(earlier.prompts_sent),
+            callbacks_handled: self
+                .callbacks_handled
+                .saturating_sub(earlier.callbacks_handled),
+            alerts_sent: self.alerts_sent.saturating_sub(earlier.alerts_sent),
+        }
+    }
+}
+
+/// A flag decided by an admin, kept for daily summaries.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Flag {
+    pub time: Integer,
+    pub reason: String,
+    pub admin: UserId,
+    /// Author of the flagged message, `None` if the author is hidden.
+    pub author: Option<UserId>,
+    pub author_name: String,
+    /// The alert is skipped since the author has been reminded recently.
+    pub suppressed: bool,
+    /// Original message in main group.
+    pub message: Option<MessageId>,
+    /// Unix time when an admin marked the flag as resolved, flags stay open
+    /// until then.
+    pub resolved: Option<Integer>,
+    /// Whether `dm_template` of the reason reached the author in private
+    /// chat, `None` if nothing was sent.
+    pub dm: Option<bool>,
+}
+
+/// Moderation activity aggregated from flags.
+#[derive(Debug, Clone, Default)]
+pub struct Summary {
+    pub flags: usize,
+    /// Flag counts by reason id, most used first.
+    pub reasons: Vec<(String, usize)>,
+    /// Names, ids and flag counts of the most flagged authors.
+    pub authors: Vec<(String, Option<UserId>, usize)>,
+    /// Users banned by the bot, filled in by callers since bans aren't
+    /// flags.
+    pub bans: usize,
+    /// Appeals sent in private chat, filled in by callers as well.
+    pub appeals: usize,
+}
+
+impl Summary {
+    /// Aggregate flags, keeping at most `top` authors.
+    pub fn from_flags(flags: &[Flag], top: usize) -> Summary {
+        let mut reasons: HashMap<&str, usize> = HashMap::new();
+        let mut authors: HashMap<(&str, Option<UserId>), usize> = HashMap::new();
+        for f in flags {
+            *reasons.entry(&f.reason).or_default() += 1;
+            *authors.entry((&f.author_name, f.author)).or_default() += 1;
+        }
+
+        let mut reasons: Vec<_> = reasons
+            .into_iter()
+            .map(|(id, n)| (id.to_string(), n))
+            .collect();
+        reasons.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        let mut authors: Vec<_> = authors
+            .into_iter()
+            .map(|((name, id), n)| (name.to_string(), id, n))
+            .collect();
+        authors.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+        authors.truncate(top);
+
+        Summary {
+            flags: flags.len(),
+            reasons,
+            authors,
+            bans: 0,
+            appeals: 0,
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+
+use telegram_bot::{Integer, MessageId, UserId};
+
+use super::normalize::content_hash;
+
+// Contents are pruned once more contents than this are tracked.
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// Detects the same content posted by many users in a short window.
+///
+/// Only hashes of contents are kept, so that the index stays small.
+pub struct WaveDetector {
+    window: Integer,
+    users: usize,
+    // Posts of each content hash, oldest first.
+    posts: HashMap<u64, Vec<(Integer, UserId, MessageId)>>,
+    // Time that waves were reported, keyed by content hash.
+    reported: HashMap<u64, Integer>,
+}
+
+impl WaveDetector {
+    /// Report waves once `users` distinct users post the same content in
+    /// `window` seconds, zero users never reports.
+    pub fn new(window: Integer, users: usize) -> Self {
+        WaveDetector {
+            window,
+            users,
+            posts: HashMap::new(),
+            reported: HashMap::new(),
+        }
+    }
+
+    /// Record a post of `text`, returns the messages of the wave it belongs
+    /// to if the wave is just detected. A wave is reported once per window.
+    pub fn observe(
+        &mut self,
+        text: &str,
+        user: UserId,
+        id: MessageId,
+        now: Integer,
+    ) -> Option<Vec<MessageId>> {
+        if self.users == 0 {
+            return None;
+        }
+
+        let window = self.window;
+        if self.posts.len() > PRUNE_THRESHOLD {
+            self.posts
+                .retain(|_, v| v.last().is_some_and(|(t, _, _)| now - t < window));
+            self.reported.retain(|_, t| now - *t < window);
+        }
+
+        let key = content_hash(text);
+        let posts = self.posts.entry(key).or_default();
+        posts.retain(|(t, _, _)| now - t < window);
+        posts.push((now, user, id));
+
+        let mut users: Vec<_> = posts.iter().map(|(_, u, _)| *u).collect();
+        users.sort_by_key(|u| i64::from(*u));
+        users.dedup();
+        if users.len() < self.users {
+            return None;
+        }
+        if self.reported.get(&key).is_some_and(|t| now - t < window) {
+            return None;
+        }
+
+        self.reported.insert(key, now);
+        Some(posts.iter().map(|(_, _, id)| *id).collect())
+    }
+
+    /// Whether `text` belongs to a wave reported in the window.
+    pub fn is_reported(&self, text: &str, now: Integer) -> bool {
+        self.reported
+            .get(&content_hash(text))
+            .is_some_and(|t| now - t < self.window)
+    }
+}